@@ -0,0 +1,182 @@
+use std::str::FromStr;
+
+/// A badge shown next to a user name in the chat.
+/// The number is the badge version, when it means something.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TwitchBadge {
+    Broadcaster,
+    Moderator,
+    Vip,
+    /// Subscription length in months: exact when the "badge-info" tag was sent,
+    /// otherwise rounded down to the badge tier (0, 3, 6, 12...)
+    Subscriber(u32),
+    /// First subscribers of the channel (months)
+    Founder(u32),
+    /// Amount of bits cheered in the channel (1, 100, 1000...)
+    Bits(u32),
+    Partner,
+    Turbo,
+    Premium,
+    Staff,
+    Admin,
+    GlobalMod,
+    /// Any other badge (name, version)
+    Other(String, String),
+}
+
+impl TwitchBadge {
+    /// Parses a single badge, like "subscriber/12"
+    pub fn parse(text: &str) -> Option<TwitchBadge> {
+        let mut parts = text.splitn(2, '/');
+        let name = parts.next().unwrap_or("");
+        let version = parts.next().unwrap_or("");
+        if name.is_empty() {
+            return None;
+        }
+
+        let number = u32::from_str(version).unwrap_or(0);
+        let result = match name {
+            "broadcaster" => TwitchBadge::Broadcaster,
+            "moderator" => TwitchBadge::Moderator,
+            "vip" => TwitchBadge::Vip,
+            // Tier 2 and 3 badges are 2000 + months and 3000 + months, use with_info for the exact months
+            "subscriber" => TwitchBadge::Subscriber(number % 1000),
+            "founder" => TwitchBadge::Founder(number),
+            "bits" => TwitchBadge::Bits(number),
+            "partner" => TwitchBadge::Partner,
+            "turbo" => TwitchBadge::Turbo,
+            "premium" => TwitchBadge::Premium,
+            "staff" => TwitchBadge::Staff,
+            "admin" => TwitchBadge::Admin,
+            "global_mod" => TwitchBadge::GlobalMod,
+            _ => TwitchBadge::Other(name.to_owned(), version.to_owned()),
+        };
+        Some(result)
+    }
+
+    /// Parses the value of the "badges" tag, like "moderator/1,subscriber/12"
+    pub fn parse_list(text: &str) -> Vec<TwitchBadge> {
+        text.split(',').filter_map(TwitchBadge::parse).collect()
+    }
+
+    /// Replaces the levels of the badges with the exact values from the "badge-info" tag,
+    /// like "subscriber/14" for a user who has the 12 months badge
+    pub fn with_info(badges: Vec<TwitchBadge>, info: &[TwitchBadge]) -> Vec<TwitchBadge> {
+        badges.into_iter().map(|badge| {
+            match info.iter().find(|exact| exact.name() == badge.name()) {
+                Some(&TwitchBadge::Subscriber(months)) => TwitchBadge::Subscriber(months),
+                Some(&TwitchBadge::Founder(months)) => TwitchBadge::Founder(months),
+                _ => badge,
+            }
+        }).collect()
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            &TwitchBadge::Broadcaster => "broadcaster",
+            &TwitchBadge::Moderator => "moderator",
+            &TwitchBadge::Vip => "vip",
+            &TwitchBadge::Subscriber(_) => "subscriber",
+            &TwitchBadge::Founder(_) => "founder",
+            &TwitchBadge::Bits(_) => "bits",
+            &TwitchBadge::Partner => "partner",
+            &TwitchBadge::Turbo => "turbo",
+            &TwitchBadge::Premium => "premium",
+            &TwitchBadge::Staff => "staff",
+            &TwitchBadge::Admin => "admin",
+            &TwitchBadge::GlobalMod => "global_mod",
+            &TwitchBadge::Other(ref name, _) => name.as_str(),
+        }
+    }
+
+    /// The badge's number, for the badges that have a meaningful one
+    pub fn level(&self) -> Option<u32> {
+        match self {
+            &TwitchBadge::Subscriber(months) | &TwitchBadge::Founder(months) => Some(months),
+            &TwitchBadge::Bits(amount) => Some(amount),
+            _ => None,
+        }
+    }
+
+    /// Broadcasters and moderators can moderate the channel
+    pub fn is_moderator(&self) -> bool {
+        match self {
+            &TwitchBadge::Broadcaster | &TwitchBadge::Moderator => true,
+            _ => false,
+        }
+    }
+}
+
+/// A badge that protects its owner from the hammer, with an optional minimum level
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadgeRequirement {
+    pub name: String,
+    pub min_level: u32,
+}
+
+impl BadgeRequirement {
+    /// Parses a requirement written on a single line, like "vip" or "subscriber 3"
+    pub fn parse(value: &str) -> Result<BadgeRequirement, String> {
+        let mut parts = value.split_whitespace();
+        let name = match parts.next() {
+            Some(name) => name.to_lowercase(),
+            None => return Err(format!("empty badge name")),
+        };
+        let min_level = match parts.next() {
+            Some(text) => try!(u32::from_str(text).map_err(|_| format!("invalid level in badge '{}'", value))),
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return Err(format!("invalid badge '{}'", value));
+        }
+
+        Ok(BadgeRequirement {
+            name: name,
+            min_level: min_level,
+        })
+    }
+
+    /// What is protected when nothing is configured: the channel staff and the subscribers
+    pub fn defaults() -> Vec<BadgeRequirement> {
+        ["broadcaster", "moderator", "vip", "subscriber", "founder", "staff", "admin", "global_mod"].iter()
+            .map(|name| BadgeRequirement { name: name.to_string(), min_level: 0 })
+            .collect()
+    }
+
+    pub fn matches(&self, badge: &TwitchBadge) -> bool {
+        badge.name() == self.name && badge.level().unwrap_or(0) >= self.min_level
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_badge_list() {
+        assert_eq!(vec![TwitchBadge::Moderator, TwitchBadge::Subscriber(12), TwitchBadge::Bits(1000)],
+            TwitchBadge::parse_list("moderator/1,subscriber/3012,bits/1000"));
+        assert_eq!(vec![TwitchBadge::Other("predictions".to_owned(), "blue-1".to_owned())],
+            TwitchBadge::parse_list("predictions/blue-1"));
+        assert!(TwitchBadge::parse_list("").is_empty());
+    }
+
+    #[test]
+    fn badge_requirements() {
+        let veteran = BadgeRequirement::parse("subscriber 3").unwrap();
+        assert!(veteran.matches(&TwitchBadge::Subscriber(6)));
+        assert!(!veteran.matches(&TwitchBadge::Subscriber(0)));
+        assert!(!veteran.matches(&TwitchBadge::Vip));
+        assert!(BadgeRequirement::parse("vip").unwrap().matches(&TwitchBadge::Vip));
+        assert!(BadgeRequirement::parse("subscriber three").is_err());
+    }
+
+    #[test]
+    fn badge_info_gives_exact_months() {
+        let badges = TwitchBadge::with_info(TwitchBadge::parse_list("vip/1,subscriber/3012"), &TwitchBadge::parse_list("subscriber/14"));
+        assert_eq!(vec![TwitchBadge::Vip, TwitchBadge::Subscriber(14)], badges);
+        assert!(BadgeRequirement::parse("subscriber 13").unwrap().matches(&badges[1]));
+        assert_eq!(vec![TwitchBadge::Subscriber(12)], TwitchBadge::with_info(TwitchBadge::parse_list("subscriber/3012"), &[]));
+    }
+}
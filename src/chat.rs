@@ -1,266 +1,1179 @@
 extern crate irc;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::sleep;
 
 use irc::client::prelude::*;
 use irc::client::data::command::CapSubCommand;
 use irc::client::data::message::Tag;
-use time::{Tm, now_utc};
+use time::{Duration, Tm, now_utc, precise_time_ns};
 
-use checker::Checker;
-use config::HammerConfig;
+use badges::{BadgeRequirement, TwitchBadge};
+use checker::{Action, Checker, Verdict};
+use config::{ChannelConfig, HammerConfig};
+use ladder::PunishmentLadder;
+use learning::{Learner, LearningConfig, ProposalStatus};
+use lockdown::Lockdown;
+use pending::{Expired, PendingCommands, PendingKind, RejectedKind};
+use raid::{IncomingRaidConfig, RaidDetector, RaidEvent};
+use ratelimit::{Outbox, Priority};
+use shadow::{ShadowDecision, ShadowLog};
+use tags::{MessageId, MessageTagData, RoomMode, RoomStateTags, UserNoticeTags, UserStateTags};
+use tls::TlsConnection;
+use wave::{WaveDetector, WAVE_RULE_ID};
 
 const CAP_MEMBERSHIP : &'static str = "twitch.tv/membership";
 const CAP_COMMANDS : &'static str = "twitch.tv/commands";
 const CAP_TAGS : &'static str = "twitch.tv/tags";
 
+/// Start of the error the irc crate gives for a line it could not parse, followed by the line.
+/// Built by `ServerIterator::next` in irc 0.11; check it again when upgrading the crate.
+const UNPARSED_LINE_PREFIX : &'static str = "Failed to parse message. (Message: ";
+
+/// Time between two replayed lines that do not tell when they were sent, in milliseconds
+const REPLAY_LINE_INTERVAL : i64 = 10;
+
+/// How long the bans and timeouts of the bot are remembered, so that they are not mistaken for the moderators' ones
+const RECENT_PUNISHMENT_SECS : i64 = 60;
+
+/// Seconds between two runs of the timers of the channels
+const TIMER_INTERVAL : i64 = 1;
+
+/// Messages from the server. Most of them are about a channel, which is always the first field.
 enum ChatMessage {
-    /// Incoming text message (author nickname, text, tags)
-    Message(String, String, MessageTagData),
-    // A user joined the chat (nickname)
-    Join(String),
-    /// A user left the chat (nickname)
-    Leave(String),
-    /// The channel was cleared
-    Clear,
-    /// A user was timed out (nickname, duration, reason)
-    Timeout(String, u32, Option<String>),
-    /// A user was banned (nickname, reason)
-    Ban(String, Option<String>),
-    /// Someone gained or lost operator status (nickname, is_op)
-    Operator(String, bool),
-    /// Room state
-    RoomState(RoomStateTags),
+    /// Incoming text message (channel, author nickname, text, tags)
+    Message(String, String, String, MessageTagData),
+    // A user joined the chat (channel, nickname)
+    Join(String, String),
+    /// A user left the chat (channel, nickname)
+    Leave(String, String),
+    /// The channel was cleared (channel)
+    Clear(String),
+    /// A single message was deleted (channel, author nickname, message ID, text)
+    MessageDeleted(String, String, MessageId, String),
+    /// A user was timed out (channel, nickname, duration, reason)
+    Timeout(String, String, u32, Option<String>),
+    /// A user was banned (channel, nickname, reason)
+    Ban(String, String, Option<String>),
+    /// Someone gained or lost operator status (channel, nickname, is_op)
+    Operator(String, String, bool),
+    /// Room state, or the part of it that changed (channel, tags)
+    RoomState(String, RoomStateTags),
+    /// State of the bot in a channel, after joining it or sending a message (channel, tags)
+    UserState(String, UserStateTags),
+    /// State of the bot on the whole server, after logging in
+    GlobalUserState(UserStateTags),
+    /// The server accepted our login
+    Welcome,
     /// Server capabilities acknowledgement
     Capability(Vec<String>),
     /// Invalid auth token notification
     InvalidAuthToken,
-    /// This room is now in subscribers-only mode
-    SubModeOn,
-    /// This room is already in subscribers-only mode
-    SubModeAlreadyOn,
-    /// This room is no longer in subscribers-only mode
-    SubModeOff,
-    /// This room is not in subscribers-only mode
-    SubModeAlreadyOff,
-    /// This room is now in slow mode (message minimum distance)
-    SlowModeOn(u32),
-    /// This room is no longer in slow mode
-    SlowModeOff,
-    /// This room is now in r9k mode
-    R9kModeOn,
-    /// This room is already in r9k mode
-    R9kModeAlreadyOn,
-    /// This room is no longer in r9k mode
-    R9kModeOff,
-    /// This room is not in r9k mode
-    R9kModeAlreadyOff,
-    /// Now hosting another channel (hosted channel name)
-    HostModeOn(String),
-    /// This channel is already hosting the requested channel (already hosted channel name)
-    HostModeAlreadyOn(String),
-    /// Exited host mode
-    HostModeOff,
-    /// Notifies of the numner of host commands remaining this half hour (commands number remaining)
-    HostsRemaining(u32),
-    /// This room is now in emote-only mode
-    EmoteModeOn,
-    /// This room is already in emote-only mode
-    EmoteModeAlreadyOn,
-    /// This room is no longer in emote-only mode
-    EmoteModeOff,
-    /// This room is not in emote-only mode
-    EmoteModeAlreadyOff,
-    /// This channel has been suspended
-    ChannelSuspended,
-    /// User successfully timed out (nickname, duration in seconds)
-    TimeoutConfirmed(String, u32),
-    /// User successfully banned (nickname)
-    BanConfirmed(String),
-    /// User successfully unbanned (nickname)
-    UnbanConfirmed(String),
-    /// User cannot be unbanned, because he's not banned (nickname)
-    UnbanNoBan(String),
-    /// User cannot be banned, because he's already banned (nickname)
-    BanAlreadyBanned(String),
-    /// You sent an unrecognized command (command contents)
-    UnrecognisedCommand(String),
+    /// This room is now in subscribers-only mode (channel)
+    SubModeOn(String),
+    /// This room is already in subscribers-only mode (channel)
+    SubModeAlreadyOn(String),
+    /// This room is no longer in subscribers-only mode (channel)
+    SubModeOff(String),
+    /// This room is not in subscribers-only mode (channel)
+    SubModeAlreadyOff(String),
+    /// This room is now in slow mode (channel, message minimum distance)
+    SlowModeOn(String, u32),
+    /// This room is no longer in slow mode (channel)
+    SlowModeOff(String),
+    /// This room is now in r9k mode (channel)
+    R9kModeOn(String),
+    /// This room is already in r9k mode (channel)
+    R9kModeAlreadyOn(String),
+    /// This room is no longer in r9k mode (channel)
+    R9kModeOff(String),
+    /// This room is not in r9k mode (channel)
+    R9kModeAlreadyOff(String),
+    /// Now hosting another channel (channel, hosted channel name)
+    HostModeOn(String, String),
+    /// This channel is already hosting the requested channel (channel, already hosted channel name)
+    HostModeAlreadyOn(String, String),
+    /// Exited host mode (channel)
+    HostModeOff(String),
+    /// Notifies of the numner of host commands remaining this half hour (channel, commands number remaining)
+    HostsRemaining(String, u32),
+    /// This room is now in emote-only mode (channel)
+    EmoteModeOn(String),
+    /// This room is already in emote-only mode (channel)
+    EmoteModeAlreadyOn(String),
+    /// This room is no longer in emote-only mode (channel)
+    EmoteModeOff(String),
+    /// This room is not in emote-only mode (channel)
+    EmoteModeAlreadyOff(String),
+    /// This room is now in followers-only mode (channel)
+    FollowersModeOn(String),
+    /// This room is no longer in followers-only mode (channel)
+    FollowersModeOff(String),
+    /// This channel has been suspended (channel)
+    ChannelSuspended(String),
+    /// A message was successfully deleted (channel)
+    DeleteConfirmed(String),
+    /// User successfully timed out (channel, nickname, duration in seconds)
+    TimeoutConfirmed(String, String, u32),
+    /// User successfully banned (channel, nickname)
+    BanConfirmed(String, String),
+    /// User successfully unbanned (channel, nickname)
+    UnbanConfirmed(String, String),
+    /// User cannot be unbanned, because he's not banned (channel, nickname)
+    UnbanNoBan(String, String),
+    /// User cannot be banned, because he's already banned (channel, nickname)
+    BanAlreadyBanned(String, String),
+    /// Twitch refused a moderation command, which would fail again (channel, kind of command, msg-id)
+    CommandRejected(String, RejectedKind, String),
+    /// You sent an unrecognized command (channel, command contents)
+    UnrecognisedCommand(String, String),
+    /// A user subscribed (channel, tags, message shared with the sub)
+    Sub(String, UserNoticeTags, Option<String>),
+    /// A user subscribed again (channel, tags, message shared with the sub)
+    Resub(String, UserNoticeTags, Option<String>),
+    /// A user gifted a sub to another one (channel, tags)
+    SubGift(String, UserNoticeTags),
+    /// A user gifted subs to random viewers, which are announced by SubGift right after (channel, tags)
+    SubMysteryGift(String, UserNoticeTags),
+    /// Another channel is raiding this one (channel, tags)
+    Raid(String, UserNoticeTags),
+    /// A ritual, like the greeting of a new chatter (channel, tags, message)
+    Ritual(String, UserNoticeTags, Option<String>),
+    /// Nothing came from the server for a while; only the timers have to run
+    Idle,
 }
 
-enum TwitchUserType {
-    None,
-    Mod,
-    GlobalMod,
-    Admin,
-    Staff,
-    Other(String),
+impl ChatMessage {
+    /// When Twitch sent the message, for the ones that have a timestamp
+    fn sent_at(&self) -> Option<Tm> {
+        match self {
+            &ChatMessage::Message(_, _, _, ref tags) => tags.sent_at,
+            &ChatMessage::Sub(_, ref tags, _) | &ChatMessage::Resub(_, ref tags, _) |
+            &ChatMessage::SubGift(_, ref tags) | &ChatMessage::SubMysteryGift(_, ref tags) |
+            &ChatMessage::Raid(_, ref tags) | &ChatMessage::Ritual(_, ref tags, _) => tags.sent_at,
+            _ => None,
+        }
+    }
 }
 
-impl Default for TwitchUserType {
-    fn default() -> TwitchUserType {
-        TwitchUserType::None
-    }
+/// Why the bot stopped reading messages from the server
+enum ConnectionEnd {
+    /// The connection was lost, and can be retried (whether some messages were received)
+    Lost(bool),
+    /// Something happened that would happen again after reconnecting
+    Fatal,
 }
 
-impl From<String> for TwitchUserType {
-    fn from(input: String) -> TwitchUserType {
-        match input.as_str() {
-            "" => TwitchUserType::None,
-            "mod" => TwitchUserType::Mod,
-            "global_mod" => TwitchUserType::GlobalMod,
-            "admin" => TwitchUserType::Admin,
-            "staff" => TwitchUserType::Staff,
-            _ => TwitchUserType::Other(input),
+#[derive(Debug)]
+struct ChatUser {
+    nickname: String,
+    display_name: String,
+    is_mod: bool,
+    is_paying: bool,
+    /// Badges seen on the last message of the user
+    badges: Vec<TwitchBadge>,
+    auto_ban_date: Option<Tm>,
+    /// Dates of the rule matches that have not expired yet
+    strikes: Vec<Tm>,
+    /// Date of the first message the bot saw from the user
+    first_message_date: Option<Tm>,
+    last_message_date: Option<Tm>,
+    /// Last messages of the user, to learn from them if a mod punishes the user
+    recent_messages: VecDeque<String>,
+}
+
+impl ChatUser {
+    fn new(nickname: String) -> ChatUser {
+        ChatUser {
+            nickname: nickname.clone(),
+            display_name: nickname,
+            is_mod: false,
+            is_paying: false,
+            badges: Vec::new(),
+            auto_ban_date: None,
+            strikes: Vec::new(),
+            first_message_date: None,
+            last_message_date: None,
+            recent_messages: VecDeque::new(),
         }
     }
+
+    /// Forgets the expired strikes, adds a new one and returns the number of active strikes
+    fn add_strike(&mut self, now: Tm, lifetime: Duration) -> usize {
+        self.strikes.retain(|&date| now - date < lifetime);
+        self.strikes.push(now);
+        self.strikes.len()
+    }
 }
 
-#[derive(Default)]
-struct MessageTagData {
-    //badges: Vec<TwitchBadge>, // TODO
-    color: Option<String>,
-    display_name: Option<String>,
-    //emotes: // TODO
-    id: Option<String>, // TODO: Store in a UUID/GUID type
-    is_mod: Option<bool>,
-    is_subscriber: Option<bool>,
-    is_turbo: Option<bool>,
-    room_id: Option<u32>,
-    user_id: Option<u32>,
-    user_type: Option<TwitchUserType>,
+
+/// Everything the bot knows about one of the channels it moderates
+struct ChatChannel {
+    /// IRC name of the channel, with the '#'
+    name: String,
+    outbox: Arc<Outbox>,
+    checker: Rc<Checker>,
+    /// Actions replacing the ones of the rules for this channel (rule ID -> action)
+    actions: HashMap<String, Action>,
+    all_users: HashMap<String, ChatUser>,
+    /// Whether the bot can moderate this channel, once Twitch told us with a USERSTATE
+    bot_can_moderate: Option<bool>,
+    ban_mode_enabled: bool,
+    /// Modes of the chat room, with every ROOMSTATE update merged in
+    room_state: RoomStateTags,
+    ladder: Option<PunishmentLadder>,
+    wave_detector: Option<WaveDetector>,
+    raid_detector: Option<RaidDetector>,
+    /// True when the hammer mode was turned on by the raid detector, which can then turn it off
+    raid_enabled_hammer: bool,
+    incoming_raids: Option<IncomingRaidConfig>,
+    /// Room modes turned on during raids and waves
+    lockdown: Option<Lockdown>,
+    /// Users who were chatting right before a mod enabled the hammer mode
+    protected_chatters: HashSet<String>,
+    /// When the raid or wave detectors saw the current burst start; users who only chat since then are not protected
+    burst_start: Option<Tm>,
+    /// How far back (in minutes) we look for chatters to protect when the hammer mode is enabled
+    protect_chatters_minutes: i64,
+    /// Users with one of these badges are never punished
+    protected_badges: Vec<BadgeRequirement>,
+    /// When enabled, punishments are recorded in the shadow log instead of being sent
+    shadow_mode: bool,
+    shadow_log: ShadowLog,
+    /// Moderation commands waiting for Twitch to confirm them
+    pending: PendingCommands,
+    /// Users the bot banned or timed out lately, and when. Twitch confirming the command does not remove them.
+    recently_punished: HashMap<String, Tm>,
+    /// Proposes rules from the bans and timeouts of the moderators
+    learner: Option<Learner>,
+    /// The proposals the moderators accepted
+    learned_checker: Option<Checker>,
 }
 
-impl MessageTagData {
-    fn from_tags(tags: Vec<Tag>) -> Result<MessageTagData, String> {
-        let mut result = MessageTagData {
-            ..Default::default()
+impl ChatChannel {
+    /// Creates the channel state; the settings the channel does not override come from the global configuration
+    fn new(conf: &ChannelConfig, global: &HammerConfig, checker: Rc<Checker>, outbox: Arc<Outbox>) -> ChatChannel {
+        let streamer_name = conf.name.to_lowercase();
+        let mut result = ChatChannel {
+            name: conf.irc_name(),
+            outbox: outbox,
+            checker: checker,
+            actions: conf.actions.clone(),
+            all_users: HashMap::new(),
+            bot_can_moderate: None,
+            ban_mode_enabled: false,
+            room_state: RoomStateTags::default(),
+            ladder: conf.ladder.clone().or(global.ladder.clone()),
+            wave_detector: conf.wave.clone().or(global.wave.clone()).map(WaveDetector::new),
+            raid_detector: conf.raid.clone().or(global.raid.clone()).map(RaidDetector::new),
+            raid_enabled_hammer: false,
+            incoming_raids: conf.incoming_raids.clone().or(global.incoming_raids.clone()),
+            lockdown: conf.lockdown.clone().or(global.lockdown.clone()).map(Lockdown::new),
+            protected_chatters: HashSet::new(),
+            burst_start: None,
+            protect_chatters_minutes: conf.protect_recent_chatters.or(global.protect_recent_chatters).unwrap_or(10),
+            protected_badges: conf.protected_badges.clone().or(global.protected_badges.clone()).unwrap_or(BadgeRequirement::defaults()),
+            shadow_mode: conf.shadow.or(global.shadow).unwrap_or(false),
+            shadow_log: ShadowLog::new(),
+            pending: PendingCommands::new(),
+            recently_punished: HashMap::new(),
+            learner: conf.learning.clone().or(global.learning.clone()).map(Learner::new),
+            learned_checker: None,
         };
 
-        for tag in tags {
-            let Tag(key, val_opt) = tag;
-            if let Some(val) = val_opt {
-                match key.as_str() {
-                    "badges" => { /* SKIP */ },
-                    "color" => result.color = Some(val),
-                    "display-name" => result.color = Some(val),
-                    "emotes" => { /* SKIP */ },
-                    "id" => result.id = Some(val),
-                    "mod" => result.is_mod = Some(val == "1"),
-                    "subscriber" => result.is_subscriber = Some(val == "1"),
-                    "turbo" => result.is_turbo = Some(val == "1"),
-                    "room-id" => {
-                        if let Ok(parsed) = u32::from_str(val.as_str()) {
-                            result.room_id = Some(parsed);
-                        }
-                        else {
-                            return Err(format!("Could not parse the room id '{}'", val));
-                        }
-                    },
-                    "user-id" => {
-                        if let Ok(parsed) = u32::from_str(val.as_str()) {
-                            result.user_id = Some(parsed);
-                        }
-                        else {
-                            return Err(format!("Could not parse the user id '{}'", val));
-                        }
-                    },
-                    "user-type" => result.user_type = Some(TwitchUserType::from(val)),
-                    &_ => debug!("Unexpected message tag: {}={}", key, val),
+        let mut streamer = ChatUser::new(streamer_name.clone());
+        streamer.is_mod = true;
+        result.all_users.insert(streamer_name, streamer);
+        result.load_proposals();
+
+        result
+    }
+
+    /// Handles a message written in the channel by someone else than the bot
+    fn process_text(&mut self, start_time: Tm, nickname: String, msg: String, tags: MessageTagData) {
+        self.user_ensure_exists(nickname.as_str());
+        let remembered_messages = self.learner.as_ref().map_or(0, |learner| learner.config().messages);
+        let user_is_protected;
+        let user_is_mod;
+        if let Some(user) = self.all_users.get_mut(nickname.as_str()) {
+            if let Some(badges) = tags.badges {
+                let badges = TwitchBadge::with_info(badges, tags.badge_info.as_ref().map_or(&[][..], |info| &info[..]));
+                // The badges are more reliable than the MODE messages, which can be late or missing,
+                // so they also tell when a mod was demoted
+                let has_mod_badge = badges.iter().any(TwitchBadge::is_moderator);
+                if user.is_mod != has_mod_badge {
+                    info!("'{}' is {} a moderator of {}, according to their badges", nickname, if has_mod_badge { "now" } else { "no longer" }, self.name);
+                }
+                user.is_mod = has_mod_badge;
+                user.badges = badges;
+            }
+            user_is_mod = user.is_mod;
+
+            // Update user info
+            if user.first_message_date.is_none() {
+                user.first_message_date = Some(start_time);
+            }
+            user.last_message_date = Some(start_time);
+            if remembered_messages > 0 {
+                user.recent_messages.push_back(msg.clone());
+                while user.recent_messages.len() > remembered_messages {
+                    user.recent_messages.pop_front();
                 }
             }
+
+            if let Some(display_name) = tags.display_name {
+                user.display_name = display_name;
+            }
+
+            if let Some(is_turbo) = tags.is_turbo {
+                if is_turbo {
+                    user.is_paying = true;
+                }
+            }
+
+            if tags.bits.is_some() {
+                user.is_paying = true;
+            }
+
+            user_is_protected = user_is_mod || // Don't ban mods
+                                user.is_paying || // Don't ban paying users (turbo etc..), they're not bots
+                                self.protected_badges.iter().any(|requirement| user.badges.iter().any(|badge| requirement.matches(badge))) || // Don't ban VIPs, subscribers... (see protected_badges)
+                                user.auto_ban_date.is_some() || // Don't reban unbanned users
+                                self.protected_chatters.contains(&user.nickname); // Don't ban people who were here before the raid
+        }
+        else {
+            user_is_mod = false;
+            user_is_protected = false;
+            warn!("Nickname '{}' could not be found!", nickname);
+        }
+
+        if msg == ":hammer on" {
+            if user_is_mod && self.check_bot_can_moderate() {
+                // Once the hammer is on, the recent chatters can be the bots it is fighting
+                if !self.ban_mode_enabled {
+                    self.protect_recent_chatters(start_time);
+                }
+                self.enable_hammer_mode(start_time, "⚠️ ATTENTION : Hammer mode has been enabled. Please refrain from sending messages that could look like what a bot would say!");
+            }
+        }
+        else if msg == ":hammer shadow" {
+            if user_is_mod {
+                self.shadow_mode = !self.shadow_mode;
+                info!("{} turned shadow mode {}", nickname, if self.shadow_mode { "on" } else { "off" });
+                if self.shadow_mode {
+                    self.send("Shadow mode has been enabled. I'll only write down who I would have punished.");
+                }
+                else {
+                    self.send("Shadow mode has been disabled. Punishments are real again!");
+                }
+            }
+        }
+        else if msg == ":hammer shadow report" {
+            if user_is_mod {
+                let summary = self.shadow_log.summary();
+                self.send(&summary);
+            }
+        }
+        else if msg == ":hammer shadow clear" {
+            if user_is_mod {
+                self.shadow_log.clear();
+                self.send("The shadow mode decisions have been cleared.");
+            }
+        }
+        else if msg == ":hammer queue" {
+            if user_is_mod {
+                let stats = self.outbox.stats();
+                self.send(&format!("Outgoing queue: {} moderation commands and {} messages waiting, {} dropped, {} waiting for Twitch to confirm them. Limit is {} messages per 30s.",
+                    stats.moderation_queued, stats.chat_queued, stats.dropped, self.pending.len(), stats.limit));
+            }
+        }
+        else if msg == ":hammer room" {
+            if user_is_mod {
+                let description = format!("Room state: {}", self.room_state);
+                self.send(&description);
+            }
+        }
+        else if msg == ":hammer protected" {
+            if user_is_mod {
+                self.send_protected_chatters();
+            }
+        }
+        else if msg == ":hammer protected clear" {
+            if user_is_mod {
+                info!("{} cleared the {} protected chatters", nickname, self.protected_chatters.len());
+                self.protected_chatters.clear();
+                self.send("The protected users list has been cleared.");
+            }
+        }
+        else if msg == ":hammer proposals" {
+            if user_is_mod {
+                self.send_proposals();
+            }
+        }
+        else if msg.starts_with(":hammer accept ") || msg.starts_with(":hammer reject ") {
+            if user_is_mod {
+                self.review_proposal(nickname.as_str(), msg.as_str());
+            }
+        }
+        else if msg == ":hammer off" {
+            if user_is_mod {
+                self.disable_hammer_mode("Hammer mode has been disabled. I'll stop banning now!");
+            }
+        }
+        else if !user_is_protected {
+            let caught_in_wave = self.check_wave(start_time, nickname.as_str(), tags.id, msg.as_str());
+            if self.ban_mode_enabled && !caught_in_wave {
+                let emotes = tags.emotes.unwrap_or(Vec::new());
+                let verdict = self.checker.check_in_room(msg.trim(), &emotes, &self.room_state)
+                    .or_else(|| self.learned_checker.as_ref().and_then(|checker| checker.check_in_room(msg.trim(), &emotes, &self.room_state)));
+                if let Some(verdict) = verdict {
+                    let message_ids: Vec<MessageId> = tags.id.into_iter().collect();
+                    self.apply_verdict(start_time, nickname.as_str(), &message_ids, verdict);
+                }
+            }
+        }
+    }
+
+    fn process_join(&mut self, start_time: Tm, nickname: &str) {
+        debug!("'{}' joined {}", nickname, self.name);
+        let event = match self.raid_detector {
+            Some(ref mut detector) => detector.on_join(start_time),
+            None => None,
         };
+        if let Some(event) = event {
+            self.on_raid_event(start_time, event);
+        }
+    }
 
-        Ok(result)
+    /// Subscribers, gift givers and gift recipients are paying users, who are never punished
+    fn process_subscription(&mut self, tags: &UserNoticeTags) {
+        if let Some(ref message) = tags.system_message {
+            info!("{}: {}", self.name, message);
+        }
+        let nicknames: Vec<&String> = tags.login.iter().chain(tags.recipient_login.iter()).collect();
+        for nickname in nicknames {
+            self.user_ensure_exists(nickname);
+            if let Some(user) = self.all_users.get_mut(nickname.as_str()) {
+                user.is_paying = true;
+            }
+        }
     }
-}
 
-struct RoomStateTags {
-    language: Option<String>,
-    r9k: Option<bool>,
-    subs_only: Option<bool>,
-    slow: Option<bool>,
-}
+    /// Arms the hammer when a channel that is not a friend raids, if the incoming raids are configured that way
+    fn process_raid_notice(&mut self, start_time: Tm, tags: &UserNoticeTags) {
+        let raider = match tags.login {
+            Some(ref login) => login.clone(),
+            None => {
+                warn!("Raid on {} ignored: no raiding channel", self.name);
+                return;
+            }
+        };
+        warn!("{} is raiding {} with {} viewers", raider, self.name, tags.viewer_count.unwrap_or(0));
 
-impl RoomStateTags {
-    fn from_tags_list(tags: Vec<Tag>) -> RoomStateTags {
-        let mut result = RoomStateTags {
-            language: None,
-            r9k: None,
-            subs_only: None,
-            slow: None,
+        let config = match self.incoming_raids {
+            Some(ref config) => config.clone(),
+            None => return,
         };
+        if config.is_friend(&raider) {
+            info!("{} is a friend, the raid is welcome", raider);
+        }
+        else if !config.auto_hammer {
+            debug!("Hammer mode is not armed by raids");
+        }
+        else if self.ban_mode_enabled {
+            info!("Hammer mode is already enabled");
+        }
+        else if self.check_bot_can_moderate() {
+            warn!("Unknown channel {} is raiding. Enabling hammer mode.", raider);
+            self.note_burst(start_time);
+            self.protect_recent_chatters(start_time);
+            self.enable_hammer_mode(start_time, &format!("⚠️ ATTENTION : A raid from {} just arrived, and Hammer mode has been enabled. Please refrain from sending messages that could look like what a bot would say!", raider));
+            // The raid detector turns it off once things calm down; without one, the mods have to
+            if let Some(ref mut detector) = self.raid_detector {
+                detector.on_raid_notice(start_time);
+                self.raid_enabled_hammer = true;
+            }
+        }
+    }
 
-        for tag in tags {
-            let Tag(key, val_opt) = tag;
-            if let Some(val) = val_opt {
-                match key.as_str() {
-                    "language" => result.language = Some(val),
-                    "r9k" => result.r9k = Some(val.as_str() == "1"),
-                    "subs-only" => result.subs_only = Some(val.as_str() == "1"),
-                    "slow" => result.slow = Some(val.as_str() == "1"),
-                    &_ => debug!("Unexpected room state tag: {}={}", key, val),
+    /// Merges a ROOMSTATE, which only contains what changed after the first one.
+    /// The modes it turns on confirm the lockdown as well as the NOTICEs do.
+    fn update_room_state(&mut self, update: RoomStateTags) {
+        for &mode in &[RoomMode::EmoteOnly, RoomMode::FollowersOnly, RoomMode::Slow, RoomMode::SubsOnly] {
+            if update.is_on(mode) {
+                self.confirm_room_mode(mode);
+            }
+        }
+        self.room_state.merge(update);
+        info!("Room state of {}: {}", self.name, self.room_state);
+    }
+
+    fn set_operator(&mut self, nickname: &str, is_op: bool) {
+        self.user_ensure_exists(nickname);
+        if let Some(user) = self.all_users.get_mut(nickname) {
+            info!("Setting op mode of '{}' in {} to {}", nickname, self.name, is_op);
+            user.is_mod = is_op;
+        }
+        else {
+            warn!("Nickname '{}' could not be found for setting its mod status", nickname);
+        }
+    }
+
+    fn is_moderator(&self, nickname: &str) -> bool {
+        self.all_users.get(nickname).map_or(false, |user| user.is_mod)
+    }
+
+    /// Updates what we know about the bot in this channel. Twitch sends this after every message of the bot.
+    fn set_bot_state(&mut self, my_nickname: &str, tags: UserStateTags) {
+        let can_moderate = tags.can_moderate();
+        if self.bot_can_moderate != Some(can_moderate) {
+            if can_moderate {
+                info!("The bot is a moderator of {}", self.name);
+            }
+            else {
+                warn!("The bot is not a moderator of {}; it will not be able to punish anyone", self.name);
+            }
+        }
+        self.bot_can_moderate = Some(can_moderate);
+
+        self.user_ensure_exists(my_nickname);
+        if let Some(user) = self.all_users.get_mut(my_nickname) {
+            user.is_mod = can_moderate;
+            user.badges = tags.badges;
+        }
+    }
+
+    /// The bot moderates the channel if Twitch said so, or if it got the operator status before that
+    fn bot_is_moderator(&self, my_nickname: &str) -> bool {
+        self.bot_can_moderate.unwrap_or_else(|| self.is_moderator(my_nickname))
+    }
+
+    /// Hammer mode is useless when Twitch rejects the bans. Says so in the chat if the bot is not a moderator.
+    fn check_bot_can_moderate(&self) -> bool {
+        if self.bot_can_moderate == Some(false) {
+            error!("Hammer mode cannot be enabled in {}: the bot is not a moderator", self.name);
+            self.send("I can't enable Hammer mode, I'm not a moderator of this channel. Please /mod me first!");
+            false
+        }
+        else {
+            true
+        }
+    }
+
+    fn enable_hammer_mode(&mut self, now: Tm, announcement: &str) {
+        self.ban_mode_enabled = true;
+        self.raid_enabled_hammer = false;
+        self.send(announcement);
+        self.start_lockdown(now, "hammer mode");
+    }
+
+    fn disable_hammer_mode(&mut self, announcement: &str) {
+        self.ban_mode_enabled = false;
+        self.raid_enabled_hammer = false;
+        if !self.protected_chatters.is_empty() {
+            info!("Forgetting the {} protected chatters of {}", self.protected_chatters.len(), self.name);
+        }
+        self.protected_chatters.clear();
+        self.burst_start = None;
+        self.send(announcement);
+        self.end_lockdown();
+    }
+
+    /// Turns on the configured room modes, unless the room is already locked down
+    fn start_lockdown(&mut self, now: Tm, reason: &str) {
+        if self.shadow_mode {
+            if self.lockdown.is_some() {
+                info!("Shadow mode: {} is not locked down for the {}", self.name, reason);
+            }
+            return;
+        }
+        if self.bot_can_moderate == Some(false) {
+            if self.lockdown.is_some() {
+                warn!("{} cannot be locked down for the {}: the bot is not a moderator", self.name, reason);
+            }
+            return;
+        }
+
+        let commands = match self.lockdown {
+            Some(ref mut lockdown) => lockdown.start(now, &self.room_state),
+            None => return,
+        };
+        if !commands.is_empty() {
+            warn!("Locking {} down because of the {}: {}", self.name, reason, commands.join(", "));
+            for command in commands {
+                self.send_moderation(&command);
+            }
+        }
+    }
+
+    /// Puts the room modes back the way they were before the lockdown
+    fn end_lockdown(&mut self) {
+        let commands = match self.lockdown {
+            Some(ref mut lockdown) if lockdown.is_active() => {
+                let unconfirmed = lockdown.unconfirmed();
+                if !unconfirmed.is_empty() {
+                    warn!("Twitch never confirmed these lockdown modes in {}: {:?}", self.name, unconfirmed);
                 }
+                lockdown.end()
+            },
+            _ => return,
+        };
+        info!("Lifting the lockdown of {}: {}", self.name, commands.join(", "));
+        for command in commands {
+            self.send_moderation(&command);
+        }
+    }
+
+    /// Lifts the lockdown once it lasted for the configured duration. Runs every second (see `Chat::run_timers`).
+    fn check_lockdown_end(&mut self, now: Tm) {
+        let expired = self.lockdown.as_ref().map_or(false, |lockdown| lockdown.is_active() && lockdown.is_expired(now));
+        if expired {
+            self.end_lockdown();
+        }
+    }
+
+    /// Twitch confirmed that a room mode is on
+    fn confirm_room_mode(&mut self, mode: RoomMode) {
+        if let Some(ref mut lockdown) = self.lockdown {
+            if lockdown.confirm(mode) {
+                info!("Lockdown mode {:?} confirmed in {}", mode, self.name);
             }
         }
+    }
 
-        result
+    /// Adds every user who sent a message recently to the protected chatters.
+    /// Users who started chatting after the burst the detectors saw are left out.
+    fn protect_recent_chatters(&mut self, now: Tm) {
+        if self.protect_chatters_minutes <= 0 {
+            return;
+        }
+
+        let limit = now - Duration::minutes(self.protect_chatters_minutes);
+        let burst_start = match self.burst_start {
+            Some(date) if date >= limit => date,
+            _ => now,
+        };
+        let before = self.protected_chatters.len();
+        for user in self.all_users.values() {
+            if user.last_message_date.map_or(false, |date| date >= limit) &&
+               user.first_message_date.map_or(false, |date| date < burst_start) {
+                self.protected_chatters.insert(user.nickname.clone());
+            }
+        }
+        info!("Protecting {} users who chatted in the last {} minutes ({} protected in total)",
+            self.protected_chatters.len() - before, self.protect_chatters_minutes, self.protected_chatters.len());
     }
-}
 
-#[derive(Debug)]
-struct ChatUser {
-    nickname: String,
-    display_name: String,
-    is_mod: bool,
-    is_paying: bool,
-    auto_ban_date: Option<Tm>,
-}
+    /// Remembers when a burst started, keeping the start of an attack that is still recent
+    fn note_burst(&mut self, start: Tm) {
+        let limit = start - Duration::minutes(self.protect_chatters_minutes);
+        match self.burst_start {
+            Some(date) if date >= limit && date <= start => {},
+            _ => self.burst_start = Some(start),
+        }
+    }
 
-impl ChatUser {
-    fn new(nickname: String) -> ChatUser {
-        ChatUser {
-            nickname: nickname.clone(),
-            display_name: nickname,
-            is_mod: false,
-            is_paying: false,
-            auto_ban_date: None,
+    fn send_protected_chatters(&self) {
+        // Keep the answer short enough for a chat message
+        const MAX_LISTED: usize = 20;
+
+        if self.protected_chatters.is_empty() {
+            self.send("No users are protected.");
+            return;
+        }
+
+        let mut names: Vec<&str> = self.protected_chatters.iter().map(|n| n.as_str()).collect();
+        names.sort();
+        let mut listed = names.iter().take(MAX_LISTED).cloned().collect::<Vec<&str>>().join(", ");
+        if names.len() > MAX_LISTED {
+            listed = format!("{} and {} more", listed, names.len() - MAX_LISTED);
+        }
+        self.send(&format!("{} protected users: {}", names.len(), listed));
+    }
+
+    /// A user was banned or timed out. If a human mod did it, their last messages are an example for the learner.
+    fn process_punishment(&mut self, now: Tm, nickname: &str, action: Action) {
+        self.recently_punished.retain(|_, date| now - *date < Duration::seconds(RECENT_PUNISHMENT_SECS));
+        if self.learner.is_none() {
+            return;
+        }
+        let by_bot = self.recently_punished.contains_key(nickname) ||
+                     self.pending.contains(&PendingKind::Ban(nickname.to_owned())) ||
+                     self.pending.contains(&PendingKind::Timeout(nickname.to_owned()));
+        let messages: Vec<String> = match self.all_users.get_mut(nickname) {
+            Some(ref mut user) if !by_bot && user.auto_ban_date.is_none() => user.recent_messages.drain(..).collect(),
+            _ => return,
+        };
+        // The rules already know about these messages
+        let messages: Vec<String> = messages.into_iter().filter(|message| self.checker.check(message.trim()).is_none()).collect();
+
+        let new_proposals = match self.learner {
+            Some(ref mut learner) => learner.add_example(nickname, action, &messages),
+            None => return,
+        };
+        if new_proposals.is_empty() {
+            return;
+        }
+        if let Some(ref learner) = self.learner {
+            for proposal in learner.proposals().iter().filter(|proposal| new_proposals.contains(&proposal.id)) {
+                info!("New rule proposal #{} for {}: '{}' ({}), from the messages of {}",
+                    proposal.id, self.name, proposal.text, proposal.action, proposal.nicknames.join(", "));
+            }
+        }
+        // Not announced in the chat, where the spammers would learn which messages are about to be punished
+        self.save_proposals();
+    }
+
+    fn send_proposals(&self) {
+        // Keep the answer short enough for a chat message
+        const MAX_LISTED: usize = 5;
+
+        let pending: Vec<String> = match self.learner {
+            Some(ref learner) => learner.proposals().iter()
+                .filter(|proposal| proposal.status == ProposalStatus::Pending)
+                .map(|proposal| format!("#{} '{}' ({} users, {})", proposal.id, proposal.text, proposal.nicknames.len(), proposal.action))
+                .collect(),
+            None => {
+                self.send("Learning from the moderators is not enabled.");
+                return;
+            }
+        };
+
+        if pending.is_empty() {
+            self.send("There are no rule proposals to review.");
+            return;
+        }
+        let mut listed = pending.iter().take(MAX_LISTED).cloned().collect::<Vec<String>>().join(", ");
+        if pending.len() > MAX_LISTED {
+            listed = format!("{} and {} more", listed, pending.len() - MAX_LISTED);
+        }
+        self.send(&format!("Rule proposals: {}. Use ':hammer accept <number>' or ':hammer reject <number>'.", listed));
+    }
+
+    /// Handles ":hammer accept <number>" and ":hammer reject <number>"
+    fn review_proposal(&mut self, nickname: &str, msg: &str) {
+        let accept = msg.starts_with(":hammer accept ");
+        let id = match msg.split_whitespace().nth(2).and_then(|id| id.trim_left_matches('#').parse::<usize>().ok()) {
+            Some(id) => id,
+            None => {
+                self.send("Usage: ':hammer accept <number>' or ':hammer reject <number>'");
+                return;
+            }
+        };
+
+        let (mut answer, accepted) = match self.learner {
+            Some(ref mut learner) => {
+                let result = if accept { learner.accept(id, nickname) } else { learner.reject(id) };
+                let (answer, accepted) = match result {
+                    Ok(proposal) if accept => (format!("Rule proposal #{} is now active: '{}' ({}).", proposal.id, proposal.text, proposal.action), true),
+                    Ok(proposal) => (format!("Rule proposal #{} was rejected.", proposal.id), false),
+                    Err(msg) => (msg, false),
+                };
+                (answer, accepted)
+            },
+            None => {
+                self.send("Learning from the moderators is not enabled.");
+                return;
+            }
+        };
+
+        // Only say the rule is active once it is
+        if accepted {
+            if let Err(err) = self.activate_accepted_proposals() {
+                if let Some(ref mut learner) = self.learner {
+                    learner.unaccept(id);
+                }
+                answer = format!("Rule proposal #{} could not be loaded, so it is still pending: {}", id, err);
+            }
+        }
+        info!("{}: {}", nickname, answer);
+        self.send(&answer);
+        self.save_proposals();
+    }
+
+    /// Makes the accepted proposals part of the rules of the channel
+    fn activate_accepted_proposals(&mut self) -> Result<(), String> {
+        let rules = match self.learner {
+            Some(ref learner) => learner.accepted_rules(),
+            None => return Ok(()),
+        };
+        if rules.is_empty() {
+            self.learned_checker = None;
+            return Ok(());
+        }
+        match Checker::from_string(&rules) {
+            Ok(checker) => {
+                self.learned_checker = Some(checker);
+                Ok(())
+            },
+            Err(err) => {
+                error!("The accepted rule proposals of {} could not be loaded: {}", self.name, err);
+                Err(err.to_string())
+            },
+        }
+    }
+
+    /// The review file of the proposals, if there is one
+    fn proposals_file(&self) -> Option<String> {
+        self.learner.as_ref()
+            .and_then(|learner| learner.config().file.as_ref())
+            .map(|file| file.replace("{channel}", self.name.trim_left_matches('#')))
+    }
+
+    /// Reads back the proposals written by `save_proposals` before a restart, and activates the accepted ones
+    fn load_proposals(&mut self) {
+        let path = match self.proposals_file() {
+            Some(path) => path,
+            None => return,
+        };
+        let mut content = String::new();
+        let result = match File::open(&path).and_then(|mut file| file.read_to_string(&mut content)) {
+            Ok(_) => match self.learner {
+                Some(ref mut learner) => learner.load_review_file(&content),
+                None => return,
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => Err(err.to_string()),
+        };
+        match result {
+            Ok(()) => {
+                info!("Loaded {} rule proposals for {} from {}", self.learner.as_ref().map_or(0, |learner| learner.proposals().len()), self.name, path);
+                let _ = self.activate_accepted_proposals();
+            },
+            Err(err) => {
+                // Saving new proposals would overwrite the ones we could not read
+                error!("Could not load the rule proposals of {} from {}: {}. New proposals will not be saved.", self.name, path, err);
+                self.learner = self.learner.take().map(|learner| Learner::new(LearningConfig { file: None, ..learner.config().clone() }));
+            },
+        }
+    }
+
+    /// Writes the proposals to the review file, if there is one
+    fn save_proposals(&self) {
+        let (learner, path) = match (self.learner.as_ref(), self.proposals_file()) {
+            (Some(learner), Some(path)) => (learner, path),
+            _ => return,
+        };
+        let content = learner.review_file(&self.name);
+        if let Err(err) = File::create(&path).and_then(|mut file| file.write_all(content.as_bytes())) {
+            warn!("Could not write the rule proposals to {}: {}", path, err);
+        }
+    }
+
+    /// Lets the raid detector notice that a raid is over. Runs every second (see `Chat::run_timers`).
+    fn check_raid_end(&mut self, now: Tm) {
+        let event = match self.raid_detector {
+            Some(ref mut detector) => detector.tick(now),
+            None => None,
+        };
+        if let Some(event) = event {
+            self.on_raid_event(now, event);
+        }
+    }
+
+    fn on_raid_event(&mut self, now: Tm, event: RaidEvent) {
+        let config = match self.raid_detector {
+            Some(ref detector) => detector.config().clone(),
+            None => return,
+        };
+
+        match event {
+            RaidEvent::Started(joins) => {
+                self.note_burst(now - Duration::seconds(config.window));
+                if self.ban_mode_enabled {
+                    info!("Raid detected ({} JOINs in the last {}s), but hammer mode is already enabled", joins, config.window);
+                }
+                else if self.check_bot_can_moderate() {
+                    warn!("Raid detected: {} JOINs in the last {}s (threshold is {}). Enabling hammer mode.", joins, config.window, config.joins);
+                    self.enable_hammer_mode(now, "⚠️ ATTENTION : A lot of people just joined the chat, and Hammer mode has been enabled. Please refrain from sending messages that could look like what a bot would say!");
+                    self.raid_enabled_hammer = true;
+                }
+            },
+            RaidEvent::Ended(joins) => {
+                if self.raid_enabled_hammer && self.ban_mode_enabled {
+                    info!("Raid is over: less than {} JOINs per {}s for the last {}s ({} in the last window). Disabling hammer mode.",
+                        config.joins, config.window, config.quiet_period, joins);
+                    self.disable_hammer_mode("Things calmed down, hammer mode has been disabled. I'll stop banning now!");
+                }
+                else {
+                    info!("Raid is over ({} JOINs in the last {}s)", joins, config.window);
+                }
+            },
+        }
+    }
+
+    /// Feeds a message to the wave detector, and deals with the wave members if there is one.
+    /// Returns true if the author of the message was punished for being part of a wave.
+    fn check_wave(&mut self, now: Tm, nickname: &str, message_id: Option<MessageId>, msg: &str) -> bool {
+        let (members, config) = match self.wave_detector {
+            Some(ref mut detector) => (detector.push(now, nickname, message_id, msg), detector.config().clone()),
+            None => return false,
+        };
+
+        if members.is_empty() {
+            return false;
+        }
+
+        warn!("Message wave detected: {} users not reported yet sent messages similar to '{}' in the last {}s",
+            members.len(), msg, config.window);
+        // Whether they are punished below or not, the wave is not reported again for them
+        if let Some(ref mut detector) = self.wave_detector {
+            for member in &members {
+                detector.mark_reported(&member.nickname);
+            }
+        }
+        self.note_burst(now - Duration::seconds(config.window));
+        self.start_lockdown(now, "message wave");
+
+        if config.auto_hammer && !self.ban_mode_enabled && self.check_bot_can_moderate() {
+            info!("Enabling hammer mode because of a message wave");
+            self.enable_hammer_mode(now, "⚠️ ATTENTION : A wave of bot messages was detected, and Hammer mode has been enabled. Please refrain from sending messages that could look like what a bot would say!");
+        }
+
+        if !self.ban_mode_enabled {
+            return false;
+        }
+
+        let mut caught = false;
+        for member in members {
+            caught = caught || member.nickname == nickname;
+            let verdict = Verdict {
+                rule_id: WAVE_RULE_ID.to_owned(),
+                action: config.action,
+                explicit_action: true,
+            };
+            self.apply_verdict(now, member.nickname.as_str(), &member.message_ids, verdict);
+        }
+        caught
+    }
+
+    /// Punishes the author of a message that matched a rule
+    fn apply_verdict(&mut self, now: Tm, nickname: &str, message_ids: &[MessageId], verdict: Verdict) {
+        // The channel overrides count as an action chosen for the rule
+        let (rule_action, explicit) = match self.actions.get(&verdict.rule_id) {
+            Some(&action) => (action, true),
+            None => (verdict.action, verdict.explicit_action),
+        };
+        let action = self.escalate(now, nickname, rule_action, explicit);
+        info!("Message from '{}' matched rule '{}' ({})", nickname, verdict.rule_id, action);
+        if self.shadow_mode && action != Action::Warn {
+            self.shadow_log.record(ShadowDecision {
+                date: now,
+                nickname: nickname.to_owned(),
+                rule_id: verdict.rule_id,
+                action: action,
+            });
+            return;
+        }
+
+        match action {
+            Action::Delete => {
+                if message_ids.is_empty() {
+                    warn!("Could not delete the message from '{}': it has no ID", nickname);
+                }
+                for &id in message_ids {
+                    self.delete_message(id);
+                }
+            },
+            Action::Timeout(duration) => {
+                self.recently_punished.insert(nickname.to_owned(), now);
+                self.send_tracked(PendingKind::Timeout(nickname.to_owned()), &format!("/timeout {} {}", nickname, duration));
+            },
+            Action::Ban => {
+                // rip (the auto-ban date is set once Twitch confirms it)
+                self.recently_punished.insert(nickname.to_owned(), now);
+                self.send_tracked(PendingKind::Ban(nickname.to_owned()), &format!("/ban {}", nickname));
+            },
+            Action::Warn => {},
+        }
+    }
+
+    /// Gives a strike to the user, and returns the action the punishment ladder picks for them.
+    /// Without a ladder, the rule action is used.
+    fn escalate(&mut self, now: Tm, nickname: &str, rule_action: Action, explicit: bool) -> Action {
+        if rule_action == Action::Warn {
+            // Warnings are not punishments
+            return rule_action;
+        }
+
+        if let Some(ref ladder) = self.ladder {
+            if let Some(user) = self.all_users.get_mut(nickname) {
+                let strikes = user.add_strike(now, Duration::seconds(ladder.strike_lifetime));
+                debug!("'{}' now has {} strikes", nickname, strikes);
+                return ladder.escalate(strikes, if explicit { Some(rule_action) } else { None });
+            }
+            else {
+                warn!("Nickname {} not found for counting its strikes", nickname);
+            }
+        }
+
+        rule_action
+    }
+
+    /// Removes a single message from the chat, without punishing its author
+    fn delete_message(&mut self, id: MessageId) {
+        self.send_tracked(PendingKind::Delete(id), &format!("/delete {}", id));
+    }
+
+    /// Sends a moderation command, and waits for Twitch to confirm it
+    fn send_tracked(&mut self, kind: PendingKind, command: &str) {
+        let sequence = self.send_moderation(command);
+        self.pending.push(kind, command, sequence);
+    }
+
+    /// Twitch answered a moderation command. Returns false if the bot did not send it.
+    fn resolve_command(&mut self, kind: PendingKind) -> bool {
+        match self.pending.resolve(&kind) {
+            Some(command) => {
+                debug!("'{}' confirmed in {} after {} attempts", command.command, self.name, command.attempts);
+                true
+            },
+            None => {
+                debug!("{:?} in {} was not sent by the bot", kind, self.name);
+                false
+            }
+        }
+    }
+
+    fn on_ban_confirmed(&mut self, now: Tm, nickname: &str) {
+        if self.resolve_command(PendingKind::Ban(nickname.to_owned())) {
+            if let Some(user) = self.all_users.get_mut(nickname) {
+                user.auto_ban_date = Some(now);
+            }
+            else {
+                warn!("Nickname {} not found for setting its auto-ban date", nickname);
+            }
+        }
+    }
+
+    fn on_delete_confirmed(&mut self) {
+        match self.pending.resolve_delete() {
+            Some(command) => debug!("'{}' confirmed in {}", command.command, self.name),
+            None => debug!("A message deletion in {} was not sent by the bot", self.name),
+        }
+    }
+
+    /// Twitch refused a moderation command; sending it again would not help
+    fn on_command_rejected(&mut self, kind: RejectedKind, reason: &str) {
+        match self.pending.resolve_rejected(kind) {
+            Some(command) => error!("Twitch refused '{}' in {} ({}); it will not be sent again", command.command, self.name, reason),
+            None => warn!("Twitch refused a command in {} ({}), but no such command is pending", self.name, reason),
+        }
+    }
+
+    /// Sends again the commands Twitch did not confirm in time, or gives up on them
+    fn check_pending_commands(&mut self, now: Tm) {
+        for expired in self.pending.expire(now, self.outbox.moderation_sent()) {
+            match expired {
+                Expired::Retry(command) => {
+                    warn!("Twitch did not confirm '{}' in {}; sending it again (attempt #{})", command.command, self.name, command.attempts + 1);
+                    let sequence = self.send_moderation(&command.command);
+                    self.pending.retry(command, sequence);
+                },
+                Expired::GiveUp(command) => {
+                    error!("Twitch never confirmed '{}' in {} after {} attempts", command.command, self.name, command.attempts);
+                },
+            }
+        }
+    }
+
+    fn send(&self, msg: &str) {
+        self.outbox.push(Priority::Chat, self.name.as_str(), msg);
+    }
+
+    /// Sends a moderation command, which goes before the chat messages in the outgoing queue.
+    /// Returns its sequence number in the queue.
+    fn send_moderation(&self, command: &str) -> u64 {
+        self.outbox.push(Priority::Moderation, self.name.as_str(), command)
+    }
+
+
+    fn user_ensure_exists(&mut self, nickname: &str) -> bool {
+        if self.all_users.contains_key(nickname) {
+            true
+        }
+        else {
+            let owned_nickname = nickname.to_owned();
+            // Add a new user to the list
+            self.all_users.insert(owned_nickname.clone(), ChatUser::new(owned_nickname));
+            false
         }
     }
 }
 
 pub struct Chat {
     server: IrcServer,
-    channel: String, 
-    checker: Checker,
+    /// Shared by every channel, since Twitch counts the messages per account
+    outbox: Arc<Outbox>,
     cap_membership_enabled: bool,
     cap_commands_enabled: bool,
     cap_tags_enabled: bool,
-    all_users: HashMap<String, ChatUser>,
-    ban_mode_enabled: bool,
     my_nickname: String,
+    /// Channels by IRC name
+    channels: HashMap<String, ChatChannel>,
+    /// Time of the last replayed line, when the chat is fed by `replay_line` instead of a server
+    replay_clock: Option<Tm>,
+    /// When the timers of the channels last ran
+    last_timers: Option<Tm>,
 }
 
 impl Chat {
-    pub fn new(conf : &HammerConfig) -> Chat {
-        if let Some(ref channel) = conf.channel {
-            let streamer_name = channel.to_lowercase();
-            
+    pub fn new(conf : &HammerConfig, checker: Checker, channel_checkers: HashMap<String, Checker>) -> Chat {
+        let server = Chat::connect(conf).expect("Could not connect to the IRC server");
+        let result = Chat::from_server(conf, checker, channel_checkers, server);
+        Outbox::start_sender(result.outbox.clone(), result.server.clone());
+        result
+    }
+
+    fn connect(conf: &HammerConfig) -> io::Result<IrcServer> {
+        let irc_config = conf.to_irc_config();
+        if conf.use_tls() {
+            info!("Opening a TLS connection to {}:{}", irc_config.server(), irc_config.port());
+            let connection = try!(TlsConnection::connect(irc_config.server(), irc_config.port(), conf.ca_file.as_ref().map(PathBuf::from)));
+            Ok(IrcServer::from_connection(irc_config, connection))
+        }
+        else {
+            warn!("Opening an unencrypted connection to {}:{}; the OAuth token will be sent in clear text!", irc_config.server(), irc_config.port());
+            IrcServer::from_config(irc_config)
+        }
+    }
+
+    /// Creates the chat on top of an existing server, which can be a fake one.
+    /// Nothing sends the outgoing messages until `Outbox::start_sender` is called.
+    /// The channels with their own rules use the checker loaded for them (by IRC name); the others share the given checker.
+    pub fn from_server(conf : &HammerConfig, checker: Checker, mut channel_checkers: HashMap<String, Checker>, server: IrcServer) -> Chat {
+        if let Some(ref channels) = conf.channels {
             let mut result = Chat {
-                server: IrcServer::from_config(conf.to_irc_config()).unwrap(),
-                channel: format!("#{}", streamer_name),
-                checker: Checker::new(),
+                server: server,
+                outbox: Arc::new(Outbox::new()),
                 cap_membership_enabled: false,
                 cap_commands_enabled: false,
                 cap_tags_enabled: false,
-                ban_mode_enabled: false,
-                all_users: HashMap::new(),
                 my_nickname: conf.username.clone().unwrap(),
+                channels: HashMap::new(),
+                replay_clock: None,
+                last_timers: None,
             };
 
-            let mut streamer = ChatUser::new(streamer_name.clone());
-            streamer.is_mod = true;
-            result.all_users.insert(streamer_name, streamer);
+            let default_checker = Rc::new(checker);
+            for channel_conf in channels {
+                let checker = match channel_checkers.remove(&channel_conf.irc_name()) {
+                    Some(checker) => Rc::new(checker),
+                    None => default_checker.clone(),
+                };
+                let channel = ChatChannel::new(channel_conf, conf, checker, result.outbox.clone());
+                result.channels.insert(channel.name.clone(), channel);
+            }
+
+            // The bot may run on the streamer's account
+            result.update_rate_limit();
 
             result
         }
@@ -271,55 +1184,180 @@ impl Chat {
     }
 
     pub fn run(&mut self) {
-        info!("Connecting to IRC for channel {} ...", self.channel);
-        self.server.identify().unwrap();
-        info!("Connected!");
+        let mut failed_attempts = 0;
+        loop {
+            if failed_attempts > 0 {
+                let delay = Chat::reconnect_delay(failed_attempts);
+                info!("Reconnecting in {}ms (attempt #{})", delay.num_milliseconds(), failed_attempts);
+                sleep(delay.to_std().unwrap());
+                if let Err(err) = self.server.reconnect() {
+                    warn!("Could not reconnect: {}", err);
+                    failed_attempts += 1;
+                    continue;
+                }
+            }
 
-        // activate Twitch capabilities
-        // https://github.com/justintv/Twitch-API/blob/master/IRC.md
-        self.server.send_cap_req(&[
-            Capability::Custom(CAP_MEMBERSHIP), 
-            Capability::Custom(CAP_COMMANDS),
-            Capability::Custom(CAP_TAGS)]).expect("Could not send capability requests");
+            info!("Connecting to IRC for {} channels ...", self.channels.len());
+            if let Err(err) = self.server.identify() {
+                warn!("Could not identify: {}", err);
+                failed_attempts += 1;
+                continue;
+            }
+            info!("Connected!");
 
+            match self.read_until_disconnected() {
+                ConnectionEnd::Fatal => break,
+                ConnectionEnd::Lost(received_messages) => {
+                    warn!("Lost the connection to the server");
+                    // Only back off further when the server does not even talk to us
+                    failed_attempts = if received_messages { 1 } else { failed_attempts + 1 };
+                },
+            }
+        }
+
+        info!("Disconnected from server");
+    }
+
+    /// Processes messages until the connection is lost, or a fatal error happens
+    fn read_until_disconnected(&mut self) -> ConnectionEnd {
+        let mut received_messages = false;
         loop {
             if let Some(message) = self.read_next_message() {
-                if !self.process_message(message) {
-                    break;
+                match message {
+                    ChatMessage::Idle => {},
+                    _ => received_messages = true,
+                }
+                if !self.process_message(message, now_utc()) {
+                    return ConnectionEnd::Fatal;
                 }
             }
             else {
-                // No more messages; exit
-                break;
+                // No more messages
+                return ConnectionEnd::Lost(received_messages);
             }
         }
+    }
 
-        info!("Disconnected from server");
+    /// Exponential backoff (1s, 2s, 4s... up to 5 minutes), plus up to 50% of random jitter
+    /// so that several bots do not hammer the server at the same time
+    fn reconnect_delay(failed_attempts: u32) -> Duration {
+        const BASE_DELAY_MS: i64 = 1000;
+        const MAX_DELAY_MS: i64 = 5 * 60 * 1000;
+
+        let exponent = if failed_attempts > 16 { 16 } else { failed_attempts - 1 };
+        let mut delay = BASE_DELAY_MS * (1 << exponent);
+        if delay > MAX_DELAY_MS {
+            delay = MAX_DELAY_MS;
+        }
+        // The clock's nanoseconds are random enough for this
+        let jitter = (precise_time_ns() % (delay as u64 / 2 + 1)) as i64;
+        Duration::milliseconds(delay + jitter)
+    }
+
+    /// Activates the Twitch capabilities. This has to be done again after every reconnection.
+    /// https://github.com/justintv/Twitch-API/blob/master/IRC.md
+    fn request_capabilities(&mut self) {
+        self.cap_membership_enabled = false;
+        self.cap_commands_enabled = false;
+        self.cap_tags_enabled = false;
+        if let Err(err) = self.server.send_cap_req(&[
+            Capability::Custom(CAP_MEMBERSHIP), 
+            Capability::Custom(CAP_COMMANDS),
+            Capability::Custom(CAP_TAGS)]) {
+            error!("Could not send capability requests: {}", err);
+        }
+    }
+
+    /// Processes a raw IRC line as if it had been received from the server.
+    /// The time of the chat follows the timestamps of the replayed lines instead of the clock.
+    pub fn replay_line(&mut self, line: &str) -> Result<(), String> {
+        let message = try!(Chat::parse_line(line));
+        if let Some(message) = Chat::parse_message(message) {
+            let now = self.replay_time(message.sent_at());
+            self.process_message(message, now);
+        }
+        // Nothing would be sent otherwise; the replay does not care about rate limits
+        self.flush_outbox();
+        Ok(())
     }
 
-    /// Waits for the next message from the server and returns it.
+    /// Moves the replay clock to the next line: to its timestamp if it has one and it is not in the past,
+    /// otherwise a bit after the previous line. The first line without a timestamp starts at the current time.
+    fn replay_time(&mut self, sent_at: Option<Tm>) -> Tm {
+        let now = match (self.replay_clock, sent_at) {
+            (Some(previous), Some(date)) if date > previous => date,
+            (Some(previous), _) => previous + Duration::milliseconds(REPLAY_LINE_INTERVAL),
+            (None, Some(date)) => date,
+            (None, None) => now_utc(),
+        };
+        self.replay_clock = Some(now);
+        now
+    }
+
+    /// Returns everything that was sent to the server so far.
+    /// Only connections that record what they send (like the mock connection) support this.
+    pub fn sent_log(&self) -> Option<String> {
+        self.server.conn().written(self.server.config().encoding())
+    }
+
+    /// Waits for the next message from the server and returns it, or `Idle` when nothing arrives for a while.
     fn read_next_message(&self) -> Option<ChatMessage> {
         for msg in self.server.iter() {
-            match msg {
-                Ok(result) => {
-                    debug!("Message received : {}", result);
-                    let result = Chat::parse_message(result);
-                    if result.is_some() {
-                        return result;
+            let message = match msg {
+                Ok(message) => message,
+                Err(ref err) if Chat::is_idle_tick(err) => return Some(ChatMessage::Idle),
+                Err(err) => match Chat::recover_unparsed_line(&err) {
+                    Some(message) => message,
+                    None => {
+                        debug!("Error while reading a message: {}", err);
+                        continue;
                     }
-                    // if result is none, we skip that message and wait for the next one
                 },
-                Err(err) => debug!("Error while reading a message: {}", err), 
+            };
+
+            debug!("Message received : {}", message);
+            let result = Chat::parse_message(message);
+            if result.is_some() {
+                return result;
             }
+            // if result is none, we skip that message and wait for the next one
         };
 
         return None;
     }
 
+    /// Parses a raw IRC line. The irc crate cannot parse the commands without parameters (like GLOBALUSERSTATE)
+    /// unless a space follows them, so this is tried as well.
+    fn parse_line(line: &str) -> Result<Message, String> {
+        let line = line.trim_right_matches(|c| c == '\r' || c == '\n');
+        // The parser expects the line terminator to be there
+        match format!("{}\r\n", line).parse::<Message>() {
+            Ok(message) => Ok(message),
+            Err(_) => format!("{} \r\n", line).parse::<Message>().map_err(|err| err.to_owned()),
+        }
+    }
+
+    /// Tells if the irc crate failed to parse the empty line the TLS connection returns when nothing arrives.
+    /// The unencrypted connection of the irc crate never does that; the timers then only run on messages.
+    fn is_idle_tick(err: &io::Error) -> bool {
+        err.to_string() == format!("{})", UNPARSED_LINE_PREFIX)
+    }
+
+    /// Gets the line back from an error of the irc crate, and parses it with `parse_line`
+    fn recover_unparsed_line(err: &io::Error) -> Option<Message> {
+        let text = err.to_string();
+        if text.starts_with(UNPARSED_LINE_PREFIX) && text.ends_with(')') {
+            Chat::parse_line(&text[UNPARSED_LINE_PREFIX.len()..text.len() - 1]).ok()
+        }
+        else {
+            None
+        }
+    }
+
     /// Turns a raw IRC message into something easier to process for the client
     fn parse_message(message: Message) -> Option<ChatMessage> {
         match message.command {
-            Command::PRIVMSG(_, msg) => {
+            Command::PRIVMSG(channel, msg) => {
                 if let Some(msgtags) = message.tags { // We should have tags
                     if let Some(prefix) = message.prefix { // We should have a prefix
                         match MessageTagData::from_tags(msgtags) {
@@ -327,6 +1365,7 @@ impl Chat {
                                 if let Some(nickname) = Chat::parse_user_name_from_prefix(prefix.as_str()) {
                                     debug!("nickname is {}", nickname);
                                     Some(ChatMessage::Message(
+                                        channel,
                                         nickname.to_owned(),
                                         msg,
                                         tags,
@@ -353,6 +1392,7 @@ impl Chat {
                     None
                 }
             },
+            Command::Response(Response::RPL_WELCOME, _, _) => Some(ChatMessage::Welcome),
             Command::CAP(_, sub_command, _, param) => {
                 match sub_command {
                     CapSubCommand::ACK => {
@@ -367,11 +1407,11 @@ impl Chat {
                     _ => None,
                 }
             },
-            Command::MODE(_, mode, nickname_opt) => { 
+            Command::MODE(channel, mode, nickname_opt) => { 
                 if let Some(nickname) = nickname_opt {
                     match mode.as_str() {
-                        "+o" => Some(ChatMessage::Operator(nickname, true)),
-                        "-o" => Some(ChatMessage::Operator(nickname, false)),
+                        "+o" => Some(ChatMessage::Operator(channel, nickname, true)),
+                        "-o" => Some(ChatMessage::Operator(channel, nickname, false)),
                         _ => None,
                     }
                 }
@@ -379,34 +1419,48 @@ impl Chat {
                     None
                 }
             },
-            Command::NOTICE(_, content) => {
+            Command::NOTICE(channel, content) => {
                 if content == "Login authentication failed" {
                     Some(ChatMessage::InvalidAuthToken)
                 }
+                else if message.tags.is_some() {
+                    // The Twitch notices about a channel have tags
+                    Chat::parse_notice(channel, message.tags)
+                }
                 else {
                     None
                 }
             },
-            Command::JOIN(_, _, _) => {
+            Command::JOIN(ref channel, _, _) => {
                 if let Some(nickname) = Chat::parse_user_name_from_message(&message) {
-                    Some(ChatMessage::Join(nickname.to_owned()))
+                    Some(ChatMessage::Join(channel.clone(), nickname.to_owned()))
                 }
                 else {
                     warn!("JOIN dropped: no nickname");
                     None
                 }
             },
-            Command::PART(_, _) => {
+            Command::PART(ref channel, _) => {
                 if let Some(nickname) = Chat::parse_user_name_from_message(&message) {
-                    Some(ChatMessage::Leave(nickname.to_owned()))
+                    Some(ChatMessage::Leave(channel.clone(), nickname.to_owned()))
                 }
                 else {
                     warn!("PART dropped: no nickname");
                     None
                 }
             },
+            Command::Raw(ref cmdname, _, _) if cmdname.as_str() == "GLOBALUSERSTATE" => {
+                Some(ChatMessage::GlobalUserState(UserStateTags::from_tags(message.tags.unwrap_or(Vec::new()))))
+            },
             Command::Raw(cmdname, args, suffix) => {
                 debug!("Custom command '{}' reveived with args {:?} and suffix {:?}.", cmdname, args, suffix);
+                let channel = match args.first() {
+                    Some(channel) => channel.clone(),
+                    None => {
+                        warn!("Custom command '{}' dropped: no channel", cmdname);
+                        return None;
+                    }
+                };
                 match cmdname.as_str() {
                     "CLEARCHAT" => {
                         if let Some(nickname) = suffix {
@@ -432,10 +1486,10 @@ impl Chat {
                                 }
 
                                 if let Some(durval) = duration {
-                                    Some(ChatMessage::Timeout(nickname, durval, reason))
+                                    Some(ChatMessage::Timeout(channel, nickname, durval, reason))
                                 }
                                 else {
-                                    Some(ChatMessage::Ban(nickname, reason))
+                                    Some(ChatMessage::Ban(channel, nickname, reason))
                                 }
                             }
                             else {
@@ -444,7 +1498,7 @@ impl Chat {
                             }
                         }
                         else {
-                            Some(ChatMessage::Clear)
+                            Some(ChatMessage::Clear(channel))
                         }
                         // CLEAR 1s
                         // Message received : :tmi.twitch.tv CLEARCHAT #le_shtong :triplepat
@@ -459,147 +1513,66 @@ impl Chat {
                         // Custom command 'CLEARCHAT' reveived with args ["#le_shtong"] and suffix Some("triplepat").
 
                     },
+                    "CLEARMSG" => {
+                        let mut login = None;
+                        let mut id = None;
+                        for tag in message.tags.unwrap_or(Vec::new()) {
+                            let Tag(key, val_opt) = tag;
+                            if let Some(val) = val_opt {
+                                match key.as_str() {
+                                    "login" => login = Some(val),
+                                    "target-msg-id" => id = match MessageId::from_str(&val) {
+                                        Ok(id) => Some(id),
+                                        Err(msg) => {
+                                            warn!("{}", msg);
+                                            None
+                                        }
+                                    },
+                                    &_ => debug!("Unexpected CLEARMSG tag: {}={}", key, val),
+                                }
+                            }
+                        }
+
+                        match (login, id) {
+                            (Some(login), Some(id)) => Some(ChatMessage::MessageDeleted(channel, login, id, suffix.unwrap_or(String::new()))),
+                            _ => {
+                                warn!("CLEARMSG dropped: no login or message ID");
+                                None
+                            }
+                        }
+                    },
+                    "USERSTATE" => Some(ChatMessage::UserState(channel, UserStateTags::from_tags(message.tags.unwrap_or(Vec::new())))),
                     "ROOMSTATE" => {
                         if let Some(msgtags) = message.tags {
-                            Some(ChatMessage::RoomState(RoomStateTags::from_tags_list(msgtags)))
+                            Some(ChatMessage::RoomState(channel, RoomStateTags::from_tags(msgtags)))
                         }
                         else {
                             None
                         }
                     },
-                    "NOTICE" => {
+                    "NOTICE" => Chat::parse_notice(channel, message.tags),
+                    "USERNOTICE" => {
                         if let Some(tags) = message.tags {
-                            let mut msg_id_opt = None;
-                            let mut slow_duration_opt = None;
-                            let mut target_channel_opt = None;
-                            let mut number_opt = None;
-                            let mut target_user_opt = None;
-                            let mut ban_duration_opt = None;
-                            let mut invalid_command_opt = None;
-                            for tag in tags {
-                                let Tag(key, val) = tag;
-                                match key.as_str() {
-                                    "msg-id" => msg_id_opt = val,
-                                    "slow-duration" => slow_duration_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
-                                    "target-channel" => target_channel_opt = val,
-                                    "number" => number_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
-                                    "target-user" => target_user_opt = val,
-                                    "ban-duration" => ban_duration_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
-                                    "command" => invalid_command_opt = val,
-                                    &_ => debug!("Unexpected NOTICE tag: {}={:?}", key, val),
-                                }
-                            }
-
-                            if let Some(msg_id) = msg_id_opt {
-                                match msg_id.as_str() {
-                                    "subs_on" => Some(ChatMessage::SubModeOn),
-                                    "already_subs_on" => Some(ChatMessage::SubModeAlreadyOn),
-                                    "subs_off" => Some(ChatMessage::SubModeOff),
-                                    "already_subs_off" => Some(ChatMessage::SubModeAlreadyOff),
-                                    "slow_on" => match slow_duration_opt {
-                                        Some(slow_duration) => Some(ChatMessage::SlowModeOn(slow_duration)),
-                                        None => {
-                                            warn!("NOTICE for a slow mode on: no slow-duration tag");
-                                            None
-                                        }
-                                    },
-                                    "slow_off" => Some(ChatMessage::SlowModeOff),
-                                    "r9k_on" => Some(ChatMessage::R9kModeOn),
-                                    "already_r9k_on" => Some(ChatMessage::R9kModeAlreadyOn),
-                                    "r9k_off" => Some(ChatMessage::R9kModeOff),
-                                    "already_r9k_off" => Some(ChatMessage::R9kModeAlreadyOff),
-                                    "host_on" => match target_channel_opt {
-                                        Some(target_channel) => Some(ChatMessage::HostModeOn(target_channel)),
-                                        None => {
-                                            warn!("NOTICE for a channel host dropped: no target-channel tag");
-                                            None
-                                        }
-                                    },
-                                    "bad_host_hosting" => match target_channel_opt {
-                                        Some(target_channel) => Some(ChatMessage::HostModeAlreadyOn(target_channel)),
-                                        None => {
-                                            warn!("NOTICE for a channel host error dropped: no target-channel tag");
-                                            None
-                                        }
-                                    },
-                                    "host_off" => Some(ChatMessage::HostModeOff),
-                                    "hosts_remaining" => match number_opt {
-                                        Some(number) => Some(ChatMessage::HostsRemaining(number)),
-                                        None => {
-                                            warn!("NOTICE for remaining host count dropped: no number tag");
-                                            None
-                                        }
-                                    },
-                                    "emote_only_on" => Some(ChatMessage::EmoteModeOn),
-                                    "already_emote_only_on" => Some(ChatMessage::EmoteModeAlreadyOn),
-                                    "emote_only_off" => Some(ChatMessage::EmoteModeOff),
-                                    "already_emote_only_off" => Some(ChatMessage::EmoteModeAlreadyOff),
-                                    "msg_channel_suspended" => Some(ChatMessage::ChannelSuspended), // RIP
-                                    "timeout_success" => match target_user_opt {
-                                        Some(target_user) => match ban_duration_opt {
-                                            Some(ban_duration) => Some(ChatMessage::TimeoutConfirmed(target_user, ban_duration)),
-                                            None => {
-                                                warn!("NOTICE for a timeout dropped: no target-user tag");
-                                                None
-                                            }
-                                        },
-                                        None => {
-                                            warn!("NOTICE for a timeout dropped: no ban-duration tag");
-                                            None
-                                        }
-                                    },
-                                    "ban_success" => match target_user_opt {
-                                        Some(target_user) => Some(ChatMessage::BanConfirmed(target_user)),
-                                        None => {
-                                            warn!("NOTICE for a ban success dropped : no target-user tag");
-                                            None
-                                        }
-                                    },
-                                    "unban_success" => match target_user_opt {
-                                        Some(target_user) => Some(ChatMessage::UnbanConfirmed(target_user)),
-                                        None => {
-                                            warn!("NOTICE for an unban success dropped: no target-user tag");
-                                            None
-                                        }
-                                    },
-                                    "bad_unban_no_ban" => match target_user_opt {
-                                        Some(target_user) => Some(ChatMessage::UnbanNoBan(target_user)),
-                                        None => {
-                                            warn!("NOTICE for an unban failure dropped: no target-user tag");
-                                            None
-                                        }
-                                    },
-                                    "already_banned" => match target_user_opt {
-                                        Some(target_user) => Some(ChatMessage::BanAlreadyBanned(target_user)),
-                                        None => {
-                                            warn!("NOTICE for an ban failure dropped: no target-user tag");
-                                            None
-                                        }
-                                    },
-                                    "unrecognized_cmd" => match invalid_command_opt {
-                                        Some(invalid_command) => Some(ChatMessage::UnrecognisedCommand(invalid_command)),
-                                        None => {
-                                            warn!("NOTICE for an unrecognized command dropped: no command tag");
-                                            None
-                                        }
-                                    },
-                                    &_ => {
-                                        warn!("NOTICE command dropped: unknown message ID '{}'", msg_id);
-                                        None
-                                    }
+                            let tags = UserNoticeTags::from_tags(tags);
+                            let kind = tags.kind.clone().unwrap_or(String::new());
+                            match kind.as_str() {
+                                "sub" => Some(ChatMessage::Sub(channel, tags, suffix)),
+                                "resub" => Some(ChatMessage::Resub(channel, tags, suffix)),
+                                "subgift" => Some(ChatMessage::SubGift(channel, tags)),
+                                "submysterygift" => Some(ChatMessage::SubMysteryGift(channel, tags)),
+                                "raid" => Some(ChatMessage::Raid(channel, tags)),
+                                "ritual" => Some(ChatMessage::Ritual(channel, tags, suffix)),
+                                &_ => {
+                                    debug!("USERNOTICE ignored: message ID '{}'", kind);
+                                    None
                                 }
                             }
-                            else {
-                                warn!("NOTICE dropped: no message ID");
-                                None
-                            }
-
                         }
                         else {
-                            warn!("NOTICE dropped: no tags");
+                            warn!("USERNOTICE dropped: no tags");
                             None
                         }
-                    }
+                    },
                     &_ => None
                 }                
             }
@@ -610,71 +1583,221 @@ impl Chat {
         }
     }
 
-    fn process_message(&mut self, message: ChatMessage) -> bool {
-        let start_time = now_utc();
-        match message {
-            ChatMessage::Message(nickname, msg, tags) => {
-                if nickname != self.my_nickname.as_str() { // Ignore messages sent by me
-                    self.user_ensure_exists(nickname.as_str());
-                    let user_is_protected;
-                    let user_is_mod;
-                    if let Some(user) = self.all_users.get_mut(nickname.as_str()) {
-                        user_is_mod = user.is_mod;
-
-                        // Update user info
-                        if let Some(display_name) = tags.display_name {
-                            user.display_name = display_name;
-                        }
+    /// Turns a NOTICE into a message, using its "msg-id" tag
+    fn parse_notice(channel: String, tags: Option<Vec<Tag>>) -> Option<ChatMessage> {
+        if let Some(tags) = tags {
+            let mut msg_id_opt = None;
+            let mut slow_duration_opt = None;
+            let mut target_channel_opt = None;
+            let mut number_opt = None;
+            let mut target_user_opt = None;
+            let mut ban_duration_opt = None;
+            let mut invalid_command_opt = None;
+            for tag in tags {
+                let Tag(key, val) = tag;
+                match key.as_str() {
+                    "msg-id" => msg_id_opt = val,
+                    "slow-duration" => slow_duration_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
+                    "target-channel" => target_channel_opt = val,
+                    "number" => number_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
+                    "target-user" => target_user_opt = val,
+                    "ban-duration" => ban_duration_opt = val.and_then(|v| u32::from_str(v.as_str()).ok()),
+                    "command" => invalid_command_opt = val,
+                    &_ => debug!("Unexpected NOTICE tag: {}={:?}", key, val),
+                }
+            }
 
-                        if let Some(is_turbo) = tags.is_turbo {
-                            if is_turbo {
-                                user.is_paying = true;
-                            }
+            if let Some(msg_id) = msg_id_opt {
+                match msg_id.as_str() {
+                    "subs_on" => Some(ChatMessage::SubModeOn(channel)),
+                    "already_subs_on" => Some(ChatMessage::SubModeAlreadyOn(channel)),
+                    "subs_off" => Some(ChatMessage::SubModeOff(channel)),
+                    "already_subs_off" => Some(ChatMessage::SubModeAlreadyOff(channel)),
+                    "slow_on" => match slow_duration_opt {
+                        Some(slow_duration) => Some(ChatMessage::SlowModeOn(channel, slow_duration)),
+                        None => {
+                            warn!("NOTICE for a slow mode on: no slow-duration tag");
+                            None
                         }
-
-                        if let Some(is_sub) = tags.is_subscriber {
-                            if is_sub {
-                                user.is_paying = true;
-                            }
+                    },
+                    "slow_off" => Some(ChatMessage::SlowModeOff(channel)),
+                    "r9k_on" => Some(ChatMessage::R9kModeOn(channel)),
+                    "already_r9k_on" => Some(ChatMessage::R9kModeAlreadyOn(channel)),
+                    "r9k_off" => Some(ChatMessage::R9kModeOff(channel)),
+                    "already_r9k_off" => Some(ChatMessage::R9kModeAlreadyOff(channel)),
+                    "host_on" => match target_channel_opt {
+                        Some(target_channel) => Some(ChatMessage::HostModeOn(channel, target_channel)),
+                        None => {
+                            warn!("NOTICE for a channel host dropped: no target-channel tag");
+                            None
                         }
-
-                        // TODO: Check if that user bought bits
-
-                        user_is_protected = user_is_mod || // Don't ban mods
-                                            user.is_paying || // Don't ban paying users (subs, turbo etc..), they're not bots
-                                            user.auto_ban_date.is_some(); // Don't reban unbanned users
-                    }
-                    else {
-                        user_is_mod = false;
-                        user_is_protected = false;
-                        warn!("Nickname '{}' could not be found!", nickname);
-                    }
-
-                    if msg == ":hammer on" {
-                        if user_is_mod {
-                            self.ban_mode_enabled = true;
-                            self.send("⚠️ ATTENTION : Hammer mode has been enabled. Please refrain from sending messages that could look like what a bot would say!");
+                    },
+                    "bad_host_hosting" => match target_channel_opt {
+                        Some(target_channel) => Some(ChatMessage::HostModeAlreadyOn(channel, target_channel)),
+                        None => {
+                            warn!("NOTICE for a channel host error dropped: no target-channel tag");
+                            None
                         }
-                    }
-                    else if msg == ":hammer off" {
-                        if user_is_mod {
-                            self.ban_mode_enabled = false;
-                            self.send("Hammer mode has been disabled. I'll stop banning now!");
+                    },
+                    "host_off" => Some(ChatMessage::HostModeOff(channel)),
+                    "hosts_remaining" => match number_opt {
+                        Some(number) => Some(ChatMessage::HostsRemaining(channel, number)),
+                        None => {
+                            warn!("NOTICE for remaining host count dropped: no number tag");
+                            None
                         }
-                    }
-                    else if self.ban_mode_enabled {
-                        if !user_is_protected && self.checker.check(msg.trim()) {
-                            // rip
-                            self.send(&format!("/ban {}", nickname));
-                            if let Some(user) = self.all_users.get_mut(nickname.as_str()) {
-                                user.auto_ban_date = Some(now_utc());
-                            } 
-                            else {
-                                warn!("Nickname {} not found for setting its auto-ban date", nickname);
+                    },
+                    "emote_only_on" => Some(ChatMessage::EmoteModeOn(channel)),
+                    "already_emote_only_on" => Some(ChatMessage::EmoteModeAlreadyOn(channel)),
+                    "emote_only_off" => Some(ChatMessage::EmoteModeOff(channel)),
+                    "already_emote_only_off" => Some(ChatMessage::EmoteModeAlreadyOff(channel)),
+                    "followers_on" | "followers_onzero" => Some(ChatMessage::FollowersModeOn(channel)),
+                    "followers_off" => Some(ChatMessage::FollowersModeOff(channel)),
+                    "msg_channel_suspended" => Some(ChatMessage::ChannelSuspended(channel)), // RIP
+                    "delete_message_success" => Some(ChatMessage::DeleteConfirmed(channel)),
+                    "timeout_success" => match target_user_opt {
+                        Some(target_user) => match ban_duration_opt {
+                            Some(ban_duration) => Some(ChatMessage::TimeoutConfirmed(channel, target_user, ban_duration)),
+                            None => {
+                                warn!("NOTICE for a timeout dropped: no target-user tag");
+                                None
                             }
+                        },
+                        None => {
+                            warn!("NOTICE for a timeout dropped: no ban-duration tag");
+                            None
+                        }
+                    },
+                    "ban_success" => match target_user_opt {
+                        Some(target_user) => Some(ChatMessage::BanConfirmed(channel, target_user)),
+                        None => {
+                            warn!("NOTICE for a ban success dropped : no target-user tag");
+                            None
+                        }
+                    },
+                    "unban_success" => match target_user_opt {
+                        Some(target_user) => Some(ChatMessage::UnbanConfirmed(channel, target_user)),
+                        None => {
+                            warn!("NOTICE for an unban success dropped: no target-user tag");
+                            None
+                        }
+                    },
+                    "bad_unban_no_ban" => match target_user_opt {
+                        Some(target_user) => Some(ChatMessage::UnbanNoBan(channel, target_user)),
+                        None => {
+                            warn!("NOTICE for an unban failure dropped: no target-user tag");
+                            None
                         }
+                    },
+                    "already_banned" => match target_user_opt {
+                        Some(target_user) => Some(ChatMessage::BanAlreadyBanned(channel, target_user)),
+                        None => {
+                            warn!("NOTICE for an ban failure dropped: no target-user tag");
+                            None
+                        }
+                    },
+                    "bad_ban_mod" | "bad_ban_broadcaster" => Some(ChatMessage::CommandRejected(channel, RejectedKind::Ban, msg_id)),
+                    "bad_timeout_mod" | "bad_timeout_broadcaster" => Some(ChatMessage::CommandRejected(channel, RejectedKind::Timeout, msg_id)),
+                    "no_permission" => Some(ChatMessage::CommandRejected(channel, RejectedKind::Any, msg_id)),
+                    "unrecognized_cmd" => match invalid_command_opt {
+                        Some(invalid_command) => Some(ChatMessage::UnrecognisedCommand(channel, invalid_command)),
+                        None => {
+                            warn!("NOTICE for an unrecognized command dropped: no command tag");
+                            None
+                        }
+                    },
+                    &_ => {
+                        warn!("NOTICE command dropped: unknown message ID '{}'", msg_id);
+                        None
                     }
                 }
+            }
+            else {
+                warn!("NOTICE dropped: no message ID");
+                None
+            }
+
+        }
+        else {
+            warn!("NOTICE dropped: no tags");
+            None
+        }
+    }
+
+    /// Handles a message from the server, which arrived at `now` (or was sent then, for a replay)
+    fn process_message(&mut self, message: ChatMessage, now: Tm) -> bool {
+        let start_time = now_utc();
+        let mut keep_going = true;
+        self.run_timers(now);
+        match message {
+            ChatMessage::Message(channel, nickname, msg, tags) => {
+                if nickname != self.my_nickname.as_str() { // Ignore messages sent by me
+                    if let Some(channel) = self.find_channel(&channel) {
+                        channel.process_text(now, nickname, msg, tags);
+                    }
+                }
+            },
+            ChatMessage::Join(channel, nickname) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.process_join(now, &nickname);
+                }
+            },
+            ChatMessage::BanConfirmed(channel, nickname) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.on_ban_confirmed(now, &nickname);
+                }
+            },
+            ChatMessage::BanAlreadyBanned(channel, nickname) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    info!("'{}' was already banned from {}", nickname, channel.name);
+                    channel.resolve_command(PendingKind::Ban(nickname));
+                }
+            },
+            ChatMessage::CommandRejected(channel, kind, reason) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.on_command_rejected(kind, &reason);
+                }
+            },
+            ChatMessage::TimeoutConfirmed(channel, nickname, _) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.resolve_command(PendingKind::Timeout(nickname));
+                }
+            },
+            ChatMessage::DeleteConfirmed(channel) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.on_delete_confirmed();
+                }
+            },
+            ChatMessage::UnbanConfirmed(channel, nickname) | ChatMessage::UnbanNoBan(channel, nickname) => {
+                info!("'{}' is not banned from {} anymore", nickname, channel);
+            },
+            ChatMessage::SlowModeOn(channel, _) => self.confirm_room_mode(&channel, RoomMode::Slow),
+            ChatMessage::SubModeOn(channel) | ChatMessage::SubModeAlreadyOn(channel) => self.confirm_room_mode(&channel, RoomMode::SubsOnly),
+            ChatMessage::EmoteModeOn(channel) | ChatMessage::EmoteModeAlreadyOn(channel) => self.confirm_room_mode(&channel, RoomMode::EmoteOnly),
+            ChatMessage::FollowersModeOn(channel) => self.confirm_room_mode(&channel, RoomMode::FollowersOnly),
+            ChatMessage::MessageDeleted(channel, nickname, id, text) => {
+                info!("Message {} from '{}' was deleted in {}: {}", id, nickname, channel, text);
+            },
+            ChatMessage::RoomState(channel, tags) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.update_room_state(tags);
+                }
+            },
+            ChatMessage::UserState(channel, tags) => {
+                let my_nickname = self.my_nickname.to_lowercase();
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.set_bot_state(&my_nickname, tags);
+                }
+                self.update_rate_limit();
+            },
+            ChatMessage::GlobalUserState(tags) => {
+                let badges: Vec<&str> = tags.badges.iter().map(TwitchBadge::name).collect();
+                info!("Twitch knows the bot as {} (user ID {}, badges: {})", tags.display_name.unwrap_or(self.my_nickname.clone()),
+                    tags.user_id.map_or(String::from("unknown"), |id| id.to_string()), badges.join(", "));
+            },
+            ChatMessage::Welcome => {
+                info!("Logged in as {}", self.my_nickname);
+                self.request_capabilities();
             },
             ChatMessage::Capability(caps) => {
                 for cap_name in caps {
@@ -686,50 +1809,104 @@ impl Chat {
                     }
                 }
             }
-            ChatMessage::Operator(nickname, is_op) => {
-                self.user_ensure_exists(nickname.as_str());
-                if let Some(user) = self.all_users.get_mut(nickname.as_str()) {
-                    info!("Setting op mode of '{}' to {}", nickname, is_op);
-                    user.is_mod = is_op;
+            ChatMessage::Operator(channel, nickname, is_op) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.set_operator(nickname.as_str(), is_op);
                 }
-                else {
-                    warn!("Nickname '{}' could not be found for setting its mod status", nickname);
+                if nickname == self.my_nickname.to_lowercase() {
+                    self.update_rate_limit();
                 }
             }
             ChatMessage::InvalidAuthToken => {
                 error!("The remote server rejected the OAuth token. Make sure it is correct in your configuration file!");
-                // We could exit here, but we'll let the connection close by itself
+                // Reconnecting would not help
+                keep_going = false;
+            },
+            ChatMessage::Sub(channel, tags, _) | ChatMessage::Resub(channel, tags, _) |
+            ChatMessage::SubGift(channel, tags) | ChatMessage::SubMysteryGift(channel, tags) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.process_subscription(&tags);
+                }
+            },
+            ChatMessage::Raid(channel, tags) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.process_raid_notice(now, &tags);
+                }
+            },
+            ChatMessage::Ritual(channel, tags, _) => {
+                info!("{}: ritual '{}' for {}", channel, tags.ritual_name.unwrap_or(String::new()), tags.login.unwrap_or(String::new()));
+            },
+            ChatMessage::Ban(channel, nickname, _) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.process_punishment(now, &nickname, Action::Ban);
+                }
             },
-            // ChatMessage::Ban(_, _) => {
-            //     // TODO
-            // }
+            ChatMessage::Timeout(channel, nickname, duration, _) => {
+                if let Some(channel) = self.find_channel(&channel) {
+                    channel.process_punishment(now, &nickname, Action::Timeout(duration));
+                }
+            },
+            // Only the timers above had to run
+            ChatMessage::Idle => return keep_going,
             _ => {},
         }
 
         debug!("Message processsed in {}ms", (now_utc() - start_time).num_milliseconds());
-        true
+        keep_going
     }
 
-    fn send(&self, msg: &str) {
-        if let Err(error) = self.server.send_privmsg(self.channel.as_str(), msg) {
-            error!("Could not send a message on {}!", self.channel);
-            debug!(" - Message was '{}'", msg);
-            debug!(" - Error was {}", error);
+    fn confirm_room_mode(&mut self, channel: &str, mode: RoomMode) {
+        if let Some(channel) = self.find_channel(channel) {
+            channel.confirm_room_mode(mode);
         }
     }
 
-    fn user_ensure_exists(&mut self, nickname: &str) -> bool {
-        if self.all_users.contains_key(nickname) {
-            true
+    /// Ends the raids and lockdowns, and sends again the moderation commands Twitch did not confirm.
+    /// Runs once per second at most, on the messages and the `Idle` ticks of the connection.
+    fn run_timers(&mut self, now: Tm) {
+        if self.last_timers.map_or(false, |date| now - date < Duration::seconds(TIMER_INTERVAL)) {
+            return;
         }
-        else {
-            let owned_nickname = nickname.to_owned();
-            // Add a new user to the list
-            self.all_users.insert(owned_nickname.clone(), ChatUser::new(owned_nickname));
-            false
+        self.last_timers = Some(now);
+
+        // Twitch does not answer the replayed commands, which would all be sent again
+        let is_replay = self.replay_clock.is_some();
+        for channel in self.channels.values_mut() {
+            channel.check_raid_end(now);
+            channel.check_lockdown_end(now);
+            if !is_replay {
+                channel.check_pending_commands(now);
+            }
+        }
+    }
+
+    fn find_channel(&mut self, name: &str) -> Option<&mut ChatChannel> {
+        let result = self.channels.get_mut(name);
+        if result.is_none() {
+            warn!("Message for unknown channel '{}' dropped", name);
+        }
+        result
+    }
+
+    /// Twitch only raises the rate limit in the channels the bot moderates.
+    /// There is a single queue for all channels, so the bot has to moderate every one of them.
+    fn update_rate_limit(&self) {
+        let my_nickname = self.my_nickname.to_lowercase();
+        let is_moderator = self.channels.values().all(|channel| channel.bot_is_moderator(&my_nickname));
+        self.outbox.set_moderator(is_moderator);
+    }
+
+    fn flush_outbox(&self) {
+        for message in self.outbox.drain() {
+            if let Err(error) = self.server.send_privmsg(message.target.as_str(), message.text.as_str()) {
+                error!("Could not send a message on {}!", message.target);
+                debug!(" - Message was '{}'", message.text);
+                debug!(" - Error was {}", error);
+            }
         }
     }
 
+
     fn parse_user_name_from_message(message: &Message) -> Option<&str> {
         if let Some(ref prefix) = message.prefix {
             Chat::parse_user_name_from_prefix(prefix.as_str())
@@ -754,6 +1931,29 @@ impl Chat {
 #[cfg(test)]
 mod test {
     use super::*;
+    use irc::client::conn::MockConnection;
+    use learning::LearningConfig;
+    use lockdown::LockdownConfig;
+    use raid::RaidConfig;
+    use wave::{Similarity, WaveConfig};
+
+    /// Configuration of a bot moderating #streamer
+    fn test_config() -> HammerConfig {
+        let mut conf = HammerConfig::new();
+        conf.username = Some("hammer_bot".to_owned());
+        conf.channels = Some(vec![ChannelConfig::new("streamer")]);
+        conf
+    }
+
+    /// A chat on a mock connection, which records what the bot sends
+    fn chat_with_config(conf: &HammerConfig, rules: &str) -> Chat {
+        let server = IrcServer::from_connection(conf.to_irc_config(), MockConnection::empty());
+        Chat::from_server(conf, Checker::from_string(rules).unwrap(), HashMap::new(), server)
+    }
+
+    fn test_chat(rules: &str) -> Chat {
+        chat_with_config(&test_config(), rules)
+    }
 
     #[test]
     fn parse_user_name_from_prefix_correct() {
@@ -764,4 +1964,278 @@ mod test {
     fn parse_user_name_from_prefix_incorrect() {
         assert_eq!(None, Chat::parse_user_name_from_prefix("u wot?"));
     }
+
+    #[test]
+    fn recover_unparsed_line() {
+        // Same error as irc 0.11 gives, with the line as it was read from the connection
+        let line = "@badge-info=;badges=;color=;display-name=hammer_bot;emote-sets=0;user-id=141981764;user-type= :tmi.twitch.tv GLOBALUSERSTATE\r\n";
+        let err = io::Error::new(io::ErrorKind::InvalidInput, &format!("Failed to parse message. (Message: {})", line)[..]);
+        match Chat::recover_unparsed_line(&err).map(|message| message.command) {
+            Some(Command::Raw(command, _, _)) => assert_eq!("GLOBALUSERSTATE", command),
+            other => panic!("GLOBALUSERSTATE was not recovered: {:?}", other),
+        }
+
+        let other = io::Error::new(io::ErrorKind::Other, "Connection reset by peer");
+        assert!(Chat::recover_unparsed_line(&other).is_none());
+        assert!(!Chat::is_idle_tick(&err));
+
+        // The empty line of the TLS connection
+        let idle = io::Error::new(io::ErrorKind::InvalidInput, &format!("Failed to parse message. (Message: {})", "")[..]);
+        assert!(Chat::is_idle_tick(&idle));
+    }
+
+    #[test]
+    fn lockdown_ends_without_messages() {
+        let mut conf = test_config();
+        conf.lockdown = Some(LockdownConfig { followers: None, slow: None, emote_only: true, subs_only: false, duration: 60 });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        assert!(chat.sent_log().unwrap().contains("PRIVMSG #streamer :/emoteonly\r\n"));
+
+        let start = chat.replay_clock.unwrap();
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(59));
+        chat.flush_outbox();
+        assert!(!chat.sent_log().unwrap().contains("/emoteonlyoff"));
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(60));
+        chat.flush_outbox();
+        assert!(chat.sent_log().unwrap().contains("PRIVMSG #streamer :/emoteonlyoff"));
+    }
+
+    #[test]
+    fn raid_ends_without_messages() {
+        let mut conf = test_config();
+        conf.raid = Some(RaidConfig { joins: 3, window: 10, quiet_period: 30 });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        for nickname in ["raider1", "raider2", "raider3"].iter() {
+            chat.replay_line(&format!(":{0}!{0}@{0}.tmi.twitch.tv JOIN #streamer", nickname)).unwrap();
+        }
+        assert!(chat.channels["#streamer"].ban_mode_enabled);
+
+        let start = chat.replay_clock.unwrap();
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(20));
+        assert!(chat.channels["#streamer"].ban_mode_enabled);
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(31));
+        assert!(!chat.channels["#streamer"].ban_mode_enabled);
+    }
+
+    #[test]
+    fn delete_single_message() {
+        let mut chat = test_chat("- id: spam\n  pattern: buy followers\n  action: delete\n");
+
+        let clearmsg = "@login=spambot;room-id=;target-msg-id=885196de-cb67-427a-baa8-82f9b0fcd05f;tmi-sent-ts=1642720582342 :tmi.twitch.tv CLEARMSG #streamer :buy followers\r\n";
+        match Chat::parse_message(clearmsg.parse().unwrap()) {
+            Some(ChatMessage::MessageDeleted(channel, nickname, id, text)) => {
+                assert_eq!("#streamer", channel);
+                assert_eq!("spambot", nickname);
+                assert_eq!("885196de-cb67-427a-baa8-82f9b0fcd05f", id.to_string());
+                assert_eq!("buy followers", text);
+            },
+            _ => panic!("CLEARMSG was not parsed"),
+        }
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        chat.replay_line("@badges=;display-name=spambot;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=2 :spambot!spambot@spambot.tmi.twitch.tv PRIVMSG #streamer :buy followers").unwrap();
+        assert!(chat.sent_log().unwrap().contains("PRIVMSG #streamer :/delete 885196de-cb67-427a-baa8-82f9b0fcd05f"));
+        assert_eq!(1, chat.channels["#streamer"].pending.len());
+        chat.replay_line("@msg-id=delete_message_success :tmi.twitch.tv NOTICE #streamer :The message from spambot is now deleted.").unwrap();
+        assert_eq!(0, chat.channels["#streamer"].pending.len());
+    }
+
+    #[test]
+    fn hammer_needs_moderator() {
+        let mut chat = test_chat("[]");
+
+        chat.replay_line("@badge-info=;badges=;color=;display-name=hammer_bot;emote-sets=0;user-id=141981764;user-type= :tmi.twitch.tv GLOBALUSERSTATE").unwrap();
+        chat.replay_line("@badge-info=;badges=;color=;display-name=hammer_bot;emote-sets=0;mod=0;subscriber=0;user-type= :tmi.twitch.tv USERSTATE #streamer").unwrap();
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        assert!(chat.sent_log().unwrap().contains("I'm not a moderator"));
+        assert!(!chat.channels["#streamer"].ban_mode_enabled);
+
+        chat.replay_line("@badge-info=;badges=moderator/1;color=;display-name=hammer_bot;emote-sets=0;mod=1;subscriber=0;user-type=mod :tmi.twitch.tv USERSTATE #streamer").unwrap();
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        assert!(chat.channels["#streamer"].ban_mode_enabled);
+    }
+
+    #[test]
+    fn ban_date_after_confirmation() {
+        let mut chat = test_chat("- id: spam\n  pattern: buy followers\n");
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        chat.replay_line("@badges=;display-name=spambot;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=2 :spambot!spambot@spambot.tmi.twitch.tv PRIVMSG #streamer :buy followers").unwrap();
+        assert!(chat.sent_log().unwrap().contains("PRIVMSG #streamer :/ban spambot"));
+        assert_eq!(1, chat.channels["#streamer"].pending.len());
+        assert!(chat.channels["#streamer"].all_users["spambot"].auto_ban_date.is_none());
+
+        chat.replay_line("@msg-id=ban_success;target-user=spambot :tmi.twitch.tv NOTICE #streamer :spambot is now banned from this channel.").unwrap();
+        assert_eq!(0, chat.channels["#streamer"].pending.len());
+        assert!(chat.channels["#streamer"].all_users["spambot"].auto_ban_date.is_some());
+    }
+
+    #[test]
+    fn rejected_commands_are_not_retried() {
+        let mut chat = test_chat("- id: spam\n  pattern: buy followers\n");
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        chat.replay_line("@badges=;display-name=spambot;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=2 :spambot!spambot@spambot.tmi.twitch.tv PRIVMSG #streamer :buy followers").unwrap();
+        assert_eq!(1, chat.channels["#streamer"].pending.len());
+        chat.replay_line("@msg-id=bad_ban_mod :tmi.twitch.tv NOTICE #streamer :You cannot ban moderator spambot unless you are the owner of this channel.").unwrap();
+        assert_eq!(0, chat.channels["#streamer"].pending.len());
+    }
+
+    #[test]
+    fn retry_only_sent_commands() {
+        let mut chat = test_chat("[]");
+        let start = now_utc();
+        chat.channels.get_mut("#streamer").unwrap().send_tracked(PendingKind::Ban("spambot".to_owned()), "/ban spambot");
+
+        // The ban waits in the outgoing queue, which does not make it late
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(60));
+        assert_eq!(1, chat.outbox.stats().moderation_queued);
+
+        chat.flush_outbox();
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(61));
+        assert_eq!(0, chat.outbox.stats().moderation_queued);
+        chat.process_message(ChatMessage::Idle, start + Duration::seconds(71));
+        assert_eq!(1, chat.outbox.stats().moderation_queued);
+        assert_eq!(1, chat.channels["#streamer"].pending.len());
+    }
+
+    #[test]
+    fn learn_from_moderators() {
+        let mut conf = test_config();
+        conf.learning = Some(LearningConfig { min_users: 3, messages: 5, file: None });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        for (id, nickname) in ["spambot1", "spambot2", "spambot3"].iter().enumerate() {
+            chat.replay_line(&format!("@badges=;display-name={0};id=885196de-cb67-427a-baa8-82f9b0fcd0{1:02};mod=0;room-id=1;user-id={1} :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #streamer :Get viewers at bigfollows dot com", nickname, id + 10)).unwrap();
+            chat.replay_line(&format!("@ban-duration=600;room-id=1;target-user-id={} :tmi.twitch.tv CLEARCHAT #streamer :{}", id + 10, nickname)).unwrap();
+        }
+        assert_eq!(1, chat.channels["#streamer"].learner.as_ref().unwrap().proposals().len());
+        assert!(!chat.sent_log().unwrap().contains("bigfollows"));
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer accept 1").unwrap();
+        assert!(chat.sent_log().unwrap().contains("Rule proposal #1 is now active"));
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        chat.replay_line("@badges=;display-name=spambot4;id=885196de-cb67-427a-baa8-82f9b0fcd05d;mod=0;room-id=1;user-id=20 :spambot4!spambot4@spambot4.tmi.twitch.tv PRIVMSG #streamer :get viewers at bigfollows dot com").unwrap();
+        chat.flush_outbox();
+        assert!(chat.sent_log().unwrap().contains("PRIVMSG #streamer :/timeout spambot4 600"));
+    }
+
+    #[test]
+    fn do_not_learn_from_the_bot() {
+        let mut conf = test_config();
+        conf.learning = Some(LearningConfig { min_users: 1, messages: 5, file: None });
+        let mut chat = chat_with_config(&conf, "- id: spam\n  pattern: buy followers\n  action: timeout\n  duration: 600\n");
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        chat.replay_line("@badges=;display-name=spambot;id=885196de-cb67-427a-baa8-82f9b0fcd05d;mod=0;room-id=1;user-id=2 :spambot!spambot@spambot.tmi.twitch.tv PRIVMSG #streamer :check my profile for cheap viewers").unwrap();
+        chat.replay_line("@badges=;display-name=spambot;id=885196de-cb67-427a-baa8-82f9b0fcd05e;mod=0;room-id=1;user-id=2 :spambot!spambot@spambot.tmi.twitch.tv PRIVMSG #streamer :buy followers").unwrap();
+        // Twitch confirms the timeout before telling the channel about it
+        chat.replay_line("@ban-duration=600;msg-id=timeout_success;target-user=spambot :tmi.twitch.tv NOTICE #streamer :spambot has been timed out for 600 seconds.").unwrap();
+        assert_eq!(0, chat.channels["#streamer"].pending.len());
+        chat.replay_line("@ban-duration=600;room-id=1;target-user-id=2 :tmi.twitch.tv CLEARCHAT #streamer :spambot").unwrap();
+        assert!(chat.channels["#streamer"].learner.as_ref().unwrap().proposals().is_empty());
+    }
+
+    #[test]
+    fn accepted_proposals_survive_a_restart() {
+        let path = ::std::env::temp_dir().join(format!("hammer-proposals-{}-{{channel}}.yml", ::std::process::id()));
+        let mut conf = test_config();
+        conf.learning = Some(LearningConfig { min_users: 3, messages: 5, file: Some(path.to_string_lossy().into_owned()) });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        for (id, nickname) in ["spambot1", "spambot2", "spambot3"].iter().enumerate() {
+            chat.replay_line(&format!("@badges=;display-name={0};id=885196de-cb67-427a-baa8-82f9b0fcd0{1:02};mod=0;room-id=1;user-id={1} :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #streamer :Get viewers at bigfollows dot com", nickname, id + 10)).unwrap();
+            chat.replay_line(&format!("@ban-duration=600;room-id=1;target-user-id={} :tmi.twitch.tv CLEARCHAT #streamer :{}", id + 10, nickname)).unwrap();
+        }
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd05f;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer accept 1").unwrap();
+
+        let restarted = chat_with_config(&conf, "[]");
+        let file = path.to_string_lossy().replace("{channel}", "streamer");
+        let _ = ::std::fs::remove_file(&file);
+        let channel = &restarted.channels["#streamer"];
+        assert!(channel.learned_checker.as_ref().and_then(|checker| checker.check("get viewers at bigfollows dot com")).is_some());
+        assert_eq!(1, channel.learner.as_ref().unwrap().proposals().len());
+    }
+
+    #[test]
+    fn protect_chatters_from_before_the_burst() {
+        let mut chat = test_chat("[]");
+
+        chat.replay_line("@badges=;display-name=regular;id=885196de-cb67-427a-baa8-82f9b0fcd001;mod=0;room-id=1;user-id=2 :regular!regular@regular.tmi.twitch.tv PRIVMSG #streamer :hello").unwrap();
+        let burst_start = chat.replay_clock.unwrap() + Duration::milliseconds(1);
+        chat.channels.get_mut("#streamer").unwrap().note_burst(burst_start);
+        chat.replay_line("@badges=;display-name=raidbot;id=885196de-cb67-427a-baa8-82f9b0fcd002;mod=0;room-id=1;user-id=3 :raidbot!raidbot@raidbot.tmi.twitch.tv PRIVMSG #streamer :hello").unwrap();
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd003;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        assert!(chat.channels["#streamer"].protected_chatters.contains("regular"));
+        assert!(!chat.channels["#streamer"].protected_chatters.contains("raidbot"));
+
+        chat.replay_line("@badges=broadcaster/1;display-name=streamer;id=885196de-cb67-427a-baa8-82f9b0fcd004;mod=0;room-id=1;user-id=1 :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer ::hammer off").unwrap();
+        assert!(chat.channels["#streamer"].protected_chatters.is_empty());
+    }
+
+    #[test]
+    fn replay_follows_timestamps() {
+        let mut conf = test_config();
+        conf.wave = Some(WaveConfig { users: 3, window: 10, similarity: Similarity::Exact, threshold: 1.0, min_length: 5, auto_hammer: true, action: Action::Ban });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        // Messages a minute apart are not a wave, but the last three are sent within a few seconds
+        for (pos, nickname) in ["bot1", "bot2", "bot3", "bot4", "bot5"].iter().enumerate() {
+            let sent_at = 1642720000000u64 + if pos < 3 { pos as u64 * 60000 } else { 120000 + pos as u64 * 1000 };
+            chat.replay_line(&format!("@badges=;display-name={0};id=885196de-cb67-427a-baa8-82f9b0fcd0{1:02};mod=0;room-id=1;tmi-sent-ts={2};user-id={1} :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #streamer :follow me on example.com", nickname, pos + 10, sent_at)).unwrap();
+            assert_eq!(pos == 4, chat.channels["#streamer"].ban_mode_enabled);
+        }
+        assert_eq!(1642720124, chat.replay_clock.unwrap().to_timespec().sec);
+    }
+
+    #[test]
+    fn wave_punishes_each_user_once() {
+        let mut conf = test_config();
+        conf.wave = Some(WaveConfig { users: 3, window: 10, similarity: Similarity::Exact, threshold: 1.0, min_length: 5, auto_hammer: true, action: Action::Ban });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        for (pos, nickname) in ["bot1", "bot2", "bot2", "bot3"].iter().enumerate() {
+            chat.replay_line(&format!("@badges=;display-name={0};id=885196de-cb67-427a-baa8-82f9b0fcd0{1:02};mod=0;room-id=1;user-id=1{2} :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #streamer :follow me on example.com", nickname, pos + 10, &nickname[3..])).unwrap();
+        }
+        assert_eq!(1, chat.sent_log().unwrap().matches("/ban bot2").count());
+        assert_eq!(3, chat.channels["#streamer"].pending.len());
+    }
+
+    #[test]
+    fn wave_needs_moderator() {
+        let mut conf = test_config();
+        conf.wave = Some(WaveConfig { users: 3, window: 10, similarity: Similarity::Exact, threshold: 1.0, min_length: 5, auto_hammer: true, action: Action::Ban });
+        let mut chat = chat_with_config(&conf, "[]");
+
+        chat.replay_line("@badge-info=;badges=;color=;display-name=hammer_bot;emote-sets=0;mod=0;subscriber=0;user-type= :tmi.twitch.tv USERSTATE #streamer").unwrap();
+        for (pos, nickname) in ["bot1", "bot2", "bot3"].iter().enumerate() {
+            chat.replay_line(&format!("@badges=;display-name={0};id=885196de-cb67-427a-baa8-82f9b0fcd0{1:02};mod=0;room-id=1;user-id={1} :{0}!{0}@{0}.tmi.twitch.tv PRIVMSG #streamer :follow me on example.com", nickname, pos + 10)).unwrap();
+        }
+        assert!(chat.sent_log().unwrap().contains("I'm not a moderator"));
+        assert!(!chat.channels["#streamer"].ban_mode_enabled);
+    }
+
+    #[test]
+    fn demoted_moderator() {
+        let mut chat = test_chat("[]");
+
+        chat.replay_line("@badges=moderator/1;display-name=helper;id=885196de-cb67-427a-baa8-82f9b0fcd001;mod=1;room-id=1;user-id=2 :helper!helper@helper.tmi.twitch.tv PRIVMSG #streamer :hello").unwrap();
+        assert!(chat.channels["#streamer"].is_moderator("helper"));
+        chat.replay_line("@badges=;display-name=helper;id=885196de-cb67-427a-baa8-82f9b0fcd002;mod=0;room-id=1;user-id=2 :helper!helper@helper.tmi.twitch.tv PRIVMSG #streamer ::hammer on").unwrap();
+        assert!(!chat.channels["#streamer"].is_moderator("helper"));
+        assert!(!chat.channels["#streamer"].ban_mode_enabled);
+    }
+
+    #[test]
+    fn reconnect_delay_grows() {
+        let first = Chat::reconnect_delay(1).num_milliseconds();
+        assert!(first >= 1000 && first <= 1500);
+        let third = Chat::reconnect_delay(3).num_milliseconds();
+        assert!(third >= 4000 && third <= 6000);
+        let last = Chat::reconnect_delay(100).num_milliseconds();
+        assert!(last >= 300000 && last <= 450000);
+    }
 }
\ No newline at end of file
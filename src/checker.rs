@@ -1,19 +1,656 @@
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Read};
+use std::fs::File;
+use std::path::Path;
+
+use regex::{Regex, escape};
+use yaml_rust::YamlLoader;
+use yaml_rust::yaml::Yaml;
+use yaml_rust::scanner::ScanError;
+
+use emotes::{Emote, emote_ratio};
+use normalize::Normalization;
+use tags::{RoomMode, RoomStateTags};
+
+/// How the pattern of a rule is compared to the messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchKind {
+    /// The whole message must be equal to the pattern
+    Exact,
+    /// Same as Exact, but ignoring the case
+    ExactNoCase,
+    /// The pattern must appear somewhere in the message
+    Substring,
+    /// The whole message must match a glob pattern (* and ? wildcards)
+    Glob,
+    /// The message must match a regular expression
+    Regex,
+}
+
+impl MatchKind {
+    fn from_name(name: &str) -> Option<MatchKind> {
+        match name {
+            "exact" => Some(MatchKind::Exact),
+            "exact_nocase" => Some(MatchKind::ExactNoCase),
+            "substring" => Some(MatchKind::Substring),
+            "glob" => Some(MatchKind::Glob),
+            "regex" => Some(MatchKind::Regex),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with the author of a message matching a rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Only delete the offending message
+    Delete,
+    /// Time the user out (duration in seconds)
+    Timeout(u32),
+    /// Ban the user permanently
+    Ban,
+    /// Only write the match in the logs
+    Warn,
+}
+
+impl Action {
+    pub fn from_name(name: &str, duration: Option<u32>) -> Result<Action, String> {
+        match (name, duration) {
+            ("delete", None) => Ok(Action::Delete),
+            ("timeout", Some(seconds)) => Ok(Action::Timeout(seconds)),
+            ("timeout", None) => Err(format!("the 'timeout' action needs a 'duration' key (in seconds)")),
+            ("ban", None) => Ok(Action::Ban),
+            ("warn", None) => Ok(Action::Warn),
+            ("delete", Some(_)) | ("ban", Some(_)) | ("warn", Some(_)) => Err(format!("the 'duration' key can only be used with the 'timeout' action")),
+            _ => Err(format!("unknown action '{}' (expected delete, timeout, ban or warn)", name)),
+        }
+    }
+
+    /// Parses an action written on a single line, like "delete", "ban" or "timeout 60"
+    pub fn parse(value: &str) -> Result<Action, String> {
+        let mut parts = value.split_whitespace();
+        let name = parts.next().unwrap_or("");
+        let duration = match parts.next() {
+            Some(text) => match text.parse::<u32>() {
+                Ok(seconds) if seconds > 0 => Some(seconds),
+                _ => return Err(format!("invalid duration in action '{}'", value)),
+            },
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(format!("invalid action '{}'", value));
+        }
+        Action::from_name(name, duration)
+    }
+
+    /// Used to compare actions: the higher, the harsher
+    pub fn severity(&self) -> u64 {
+        match self {
+            &Action::Warn => 0,
+            &Action::Delete => 1,
+            &Action::Timeout(duration) => 2 + duration as u64,
+            &Action::Ban => u64::max_value(),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Action::Delete => write!(f, "delete"),
+            &Action::Timeout(duration) => write!(f, "timeout {}", duration),
+            &Action::Ban => write!(f, "ban"),
+            &Action::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// Result of a successful check: which rule matched, and what should be done
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub rule_id: String,
+    pub action: Action,
+    /// False when the rule uses the default action, which the punishment ladder replaces
+    pub explicit_action: bool,
+}
+
+/// Compiled form of a rule pattern
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    /// Pattern is stored in lowercase
+    ExactNoCase(String),
+    Substring(String),
+    /// Used for both globs and regexes
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// Compiles the pattern. Except for regexes, the pattern goes through the same
+    /// normalization steps as the messages it will be compared to.
+    fn compile(kind: MatchKind, pattern: &str, normalization: &Normalization) -> Result<Matcher, String> {
+        let normalized = normalization.apply(pattern);
+        let pattern = if kind == MatchKind::Regex { pattern } else { normalized.as_str() };
+        match kind {
+            MatchKind::Exact => Ok(Matcher::Exact(pattern.to_owned())),
+            MatchKind::ExactNoCase => Ok(Matcher::ExactNoCase(pattern.to_lowercase())),
+            MatchKind::Substring => Ok(Matcher::Substring(pattern.to_owned())),
+            MatchKind::Glob => Regex::new(&Matcher::glob_to_regex(pattern))
+                .map(Matcher::Regex)
+                .map_err(|err| format!("invalid glob pattern: {}", err)),
+            MatchKind::Regex => Regex::new(pattern)
+                .map(Matcher::Regex)
+                .map_err(|err| format!("invalid regular expression: {}", err)),
+        }
+    }
+
+    /// Turns a glob into an anchored regular expression
+    fn glob_to_regex(glob: &str) -> String {
+        let mut result = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => result.push_str(".*"),
+                '?' => result.push('.'),
+                _ => result.push_str(&escape(&c.to_string())),
+            }
+        }
+        result.push('$');
+        result
+    }
+
+    fn is_match(&self, input: &str) -> bool {
+        match self {
+            &Matcher::Exact(ref pattern) => pattern == input,
+            &Matcher::ExactNoCase(ref pattern) => *pattern == input.to_lowercase(),
+            &Matcher::Substring(ref pattern) => input.contains(pattern.as_str()),
+            &Matcher::Regex(ref regex) => regex.is_match(input),
+        }
+    }
+}
+
+/// Conditions on the emotes of a message. All the conditions that are set must be met.
+#[derive(Debug, Clone, Default)]
+pub struct EmoteCondition {
+    /// Minimum number of emotes in the message
+    pub min_count: Option<usize>,
+    /// Minimum share of the message made of emotes, between 0 and 1
+    pub min_ratio: Option<f64>,
+    /// The message must contain at least one of these emotes
+    pub banned_ids: Vec<String>,
+}
+
+impl EmoteCondition {
+    fn is_set(&self) -> bool {
+        self.min_count.is_some() || self.min_ratio.is_some() || !self.banned_ids.is_empty()
+    }
+
+    fn is_match(&self, text: &str, emotes: &[Emote]) -> bool {
+        self.min_count.map_or(true, |count| emotes.len() >= count) &&
+        self.min_ratio.map_or(true, |ratio| emote_ratio(text, emotes) >= ratio) &&
+        (self.banned_ids.is_empty() || emotes.iter().any(|emote| self.banned_ids.contains(&emote.id)))
+    }
+}
+
+/// A single ban rule, as loaded from the rules file
+#[derive(Debug)]
+pub struct Rule {
+    pub id: String,
+    /// A rule without a pattern only looks at the emotes
+    pub pattern: Option<String>,
+    pub kind: MatchKind,
+    pub emotes: EmoteCondition,
+    /// The rule is ignored while the room is in one of these modes
+    pub skip_when: Vec<RoomMode>,
+    pub description: Option<String>,
+    pub added_by: Option<String>,
+    pub action: Action,
+    /// False when the rule has no 'action' key
+    pub explicit_action: bool,
+    pub normalization: Normalization,
+    matcher: Option<Matcher>,
+}
+
+/// A problem found on a single entry of the rules file
+#[derive(Debug)]
+pub struct RuleEntryError {
+    /// Position of the entry in the list (starting at 1)
+    pub index: usize,
+    /// Line of the file where the entry starts, when it could be found
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RuleEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "rule #{} (line {}): {}", self.index, line, self.message),
+            None => write!(f, "rule #{}: {}", self.index, self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RuleLoadError {
+    /// The rules file could not be read
+    Io(Error),
+    /// The rules file is not valid YAML
+    Syntax(ScanError),
+    /// The file root is not a list of rules
+    NotAList,
+    /// Some entries are invalid
+    Entries(Vec<RuleEntryError>),
+}
+
+impl fmt::Display for RuleLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RuleLoadError::Io(ref err) => write!(f, "Could not read the rules file: {}", err),
+            &RuleLoadError::Syntax(ref err) => write!(f, "The rules file is not valid YAML: {}", err),
+            &RuleLoadError::NotAList => write!(f, "The rules file should contain a list of rules"),
+            &RuleLoadError::Entries(ref errors) => {
+                try!(write!(f, "The rules file contains {} invalid entries:", errors.len()));
+                for err in errors {
+                    try!(write!(f, "\n - {}", err));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 pub struct Checker {
-    ban_list: Vec<&'static str>,
+    rules: Vec<Rule>,
 }
 
 impl Checker {
-    pub fn new() -> Checker {
-        // Fill up the list with some dumb strings
-        Checker{
-            ban_list: vec![
-                "ban me!",
-                "hello",
-            ],
+    pub fn from_file<P: AsRef<Path>>(source: P) -> Result<Checker, RuleLoadError> {
+        let mut file = try!(File::open(source).map_err(RuleLoadError::Io));
+        let mut file_text = String::new();
+        try!(file.read_to_string(&mut file_text).map_err(RuleLoadError::Io));
+        Checker::from_string(&file_text)
+    }
+
+    pub fn from_string(source: &str) -> Result<Checker, RuleLoadError> {
+        let docs = try!(YamlLoader::load_from_str(source).map_err(RuleLoadError::Syntax));
+        let entries = match docs.first() {
+            Some(&Yaml::Array(ref entries)) => entries,
+            // An empty file is an empty rule list
+            None | Some(&Yaml::Null) => return Ok(Checker { rules: Vec::new() }),
+            Some(_) => return Err(RuleLoadError::NotAList),
+        };
+
+        let lines = Checker::find_entry_lines(source, entries.len());
+        let mut rules = Vec::new();
+        let mut errors = Vec::new();
+        let mut known_ids = HashSet::new();
+        for (pos, entry) in entries.iter().enumerate() {
+            let line = lines.as_ref().map(|l| l[pos]);
+            match Rule::from_yaml(entry) {
+                Ok(rule) => {
+                    if known_ids.insert(rule.id.clone()) {
+                        rules.push(rule);
+                    }
+                    else {
+                        errors.push(RuleEntryError { index: pos + 1, line: line, message: format!("the ID '{}' is already used by another rule", rule.id) });
+                    }
+                },
+                Err(message) => errors.push(RuleEntryError { index: pos + 1, line: line, message: message }),
+            }
+        }
+
+        if errors.is_empty() {
+            info!("Loaded {} ban rules", rules.len());
+            Ok(Checker { rules: rules })
+        }
+        else {
+            Err(RuleLoadError::Entries(errors))
+        }
+    }
+
+    /// Finds the line number of each top-level entry of the list.
+    /// The YAML parser does not give out positions, so this only recognizes block lists: the entries are the
+    /// dashes at the indentation of the first one. Returns None if the entry count does not match.
+    fn find_entry_lines(source: &str, expected: usize) -> Option<Vec<usize>> {
+        let is_entry = |text: &str| text == "-" || text.starts_with("- ");
+        let indent = match source.lines().map(str::trim_right).find(|text| is_entry(text.trim_left())) {
+            Some(first) => first.len() - first.trim_left().len(),
+            None => return None,
+        };
+        let lines: Vec<usize> = source.lines()
+            .map(str::trim_right)
+            .enumerate()
+            .filter(|&(_, text)| text.len() - text.trim_left().len() == indent && is_entry(text.trim_left()))
+            .map(|(pos, _)| pos + 1)
+            .collect();
+
+        if lines.len() == expected {
+            Some(lines)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Checks a message against every rule, in the order of the rules file.
+    /// The first matching rule decides what happens to the message.
+    pub fn check(&self, input: &str) -> Option<Verdict> {
+        self.check_with_emotes(input, &[])
+    }
+
+    /// Same as `check`, for a message containing emotes (as given by the "emotes" tag)
+    pub fn check_with_emotes(&self, input: &str, emotes: &[Emote]) -> Option<Verdict> {
+        self.check_in_room(input, emotes, &RoomStateTags::default())
+    }
+
+    /// Same as `check_with_emotes`, skipping the rules that do not apply to the current modes of the room
+    pub fn check_in_room(&self, input: &str, emotes: &[Emote], room: &RoomStateTags) -> Option<Verdict> {
+        // Most rules share the same normalization steps, so only run each pipeline once
+        let mut normalized: HashMap<Normalization, String> = HashMap::new();
+        self.rules.iter()
+            .find(|rule| {
+                if rule.skip_when.iter().any(|&mode| room.is_on(mode)) {
+                    return false;
+                }
+                if !rule.emotes.is_match(input, emotes) {
+                    return false;
+                }
+                match rule.matcher {
+                    Some(ref matcher) => {
+                        let text = normalized.entry(rule.normalization).or_insert_with(|| rule.normalization.apply(input));
+                        matcher.is_match(text)
+                    },
+                    None => true,
+                }
+            })
+            .map(|rule| Verdict {
+                rule_id: rule.id.clone(),
+                action: rule.action,
+                explicit_action: rule.explicit_action,
+            })
+    }
+}
+
+impl Rule {
+    fn from_yaml(entry: &Yaml) -> Result<Rule, String> {
+        let hash = match entry {
+            &Yaml::Hash(ref h) => h,
+            _ => return Err(format!("the entry should be a map with at least an 'id' and a 'pattern' key")),
+        };
+
+        let mut id = None;
+        let mut pattern = None;
+        let mut kind = MatchKind::Exact;
+        let mut normalization = Normalization::all();
+        let mut description = None;
+        let mut added_by = None;
+        let mut action_name = None;
+        let mut duration = None;
+        let mut emotes = EmoteCondition::default();
+        let mut skip_when = Vec::new();
+        for (k, v) in hash {
+            match k {
+                &Yaml::String(ref keyval) => {
+                    match keyval.as_ref() {
+                        "id" => id = Some(try!(Rule::read_string(v, "id"))),
+                        "pattern" => pattern = Some(try!(Rule::read_string(v, "pattern"))),
+                        "match" => {
+                            let kind_name = try!(Rule::read_string(v, "match"));
+                            kind = try!(MatchKind::from_name(&kind_name).ok_or(format!(
+                                "unknown match kind '{}' (expected exact, exact_nocase, substring, glob or regex)", kind_name)));
+                        },
+                        "normalize" => normalization = try!(Normalization::from_yaml(v)),
+                        "action" => action_name = Some(try!(Rule::read_string(v, "action"))),
+                        "duration" => duration = match v {
+                            &Yaml::Integer(value) if value > 0 && value <= u32::max_value() as i64 => Some(value as u32),
+                            _ => return Err(format!("the 'duration' key should be a positive number of seconds ({:?})", v)),
+                        },
+                        "description" => description = Some(try!(Rule::read_string(v, "description"))),
+                        "added_by" => added_by = Some(try!(Rule::read_string(v, "added_by"))),
+                        "min_emotes" => emotes.min_count = match v {
+                            &Yaml::Integer(value) if value > 0 => Some(value as usize),
+                            _ => return Err(format!("the 'min_emotes' key should be a positive number ({:?})", v)),
+                        },
+                        "min_emote_ratio" => emotes.min_ratio = match (v, v.as_f64()) {
+                            (&Yaml::Real(_), Some(value)) if value > 0.0 && value <= 1.0 => Some(value),
+                            (&Yaml::Integer(1), _) => Some(1.0),
+                            _ => return Err(format!("the 'min_emote_ratio' key should be between 0 and 1 ({:?})", v)),
+                        },
+                        "emotes" => emotes.banned_ids = match v {
+                            &Yaml::String(ref value) => vec![value.clone()],
+                            &Yaml::Integer(value) => vec![value.to_string()],
+                            &Yaml::Array(ref values) => try!(values.iter().map(|id| match id {
+                                &Yaml::String(ref value) => Ok(value.clone()),
+                                &Yaml::Integer(value) => Ok(value.to_string()),
+                                _ => Err(format!("the 'emotes' key should be a list of emote IDs ({:?})", id)),
+                            }).collect()),
+                            _ => return Err(format!("the 'emotes' key should be a list of emote IDs ({:?})", v)),
+                        },
+                        "skip_when" => skip_when = match v {
+                            &Yaml::Array(ref values) => try!(values.iter().map(|mode| match mode.as_str().and_then(RoomMode::from_name) {
+                                Some(mode) => Ok(mode),
+                                None => Err(format!("unknown room mode {:?} in 'skip_when' (expected emote_only, followers_only, r9k, slow or subs_only)", mode)),
+                            }).collect()),
+                            _ => return Err(format!("the 'skip_when' key should be a list of room modes ({:?})", v)),
+                        },
+                        &_ => return Err(format!("unknown key '{}'", keyval)),
+                    }
+                },
+                _ => return Err(format!("non-string key found ({:?})", k)),
+            }
+        }
+
+        let id = match id {
+            Some(ref value) if value.trim().is_empty() => return Err(format!("the 'id' key is empty")),
+            Some(value) => value,
+            None => return Err(format!("the 'id' key is missing")),
+        };
+
+        let pattern = match pattern {
+            Some(ref value) if value.is_empty() => return Err(format!("the 'pattern' key of rule '{}' is empty", id)),
+            Some(value) => Some(value),
+            None if emotes.is_set() => None,
+            None => return Err(format!("the 'pattern' key of rule '{}' is missing", id)),
+        };
+
+        // Banning is what every rule did before actions existed, so keep it as the default
+        let action = try!(Action::from_name(action_name.as_ref().map(|n| n.as_str()).unwrap_or("ban"), duration)
+            .map_err(|err| format!("rule '{}': {}", id, err)));
+
+        let matcher = match pattern {
+            Some(ref pattern) => Some(try!(Matcher::compile(kind, pattern, &normalization).map_err(|err| format!("rule '{}' has an {}", id, err)))),
+            None => None,
+        };
+
+        Ok(Rule {
+            id: id,
+            pattern: pattern,
+            kind: kind,
+            emotes: emotes,
+            skip_when: skip_when,
+            description: description,
+            added_by: added_by,
+            explicit_action: action_name.is_some(),
+            action: action,
+            normalization: normalization,
+            matcher: matcher,
+        })
+    }
+
+    fn read_string(token: &Yaml, val_key: &str) -> Result<String, String> {
+        match token {
+            &Yaml::String(ref value) => Ok(value.clone()),
+            _ => Err(format!("the value of '{}' should be a string ({:?})", val_key, token)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load_rules_correct() {
+        let checker = Checker::from_string("
+- id: ban-me
+  pattern: ban me!
+  description: Test rule
+  added_by: Shtong
+- id: hello
+  pattern: hello
+").unwrap();
+        assert_eq!(2, checker.rules().len());
+        assert_eq!(Some("Shtong".to_owned()), checker.rules()[0].added_by);
+        assert!(checker.check("hello").is_some());
+        assert!(checker.check("hello you").is_none());
+    }
+
+    #[test]
+    fn load_rules_reports_lines() {
+        let result = Checker::from_string("
+- id: ok
+  pattern: fine
+- id: no-pattern
+- id: ok
+  pattern: duplicated
+");
+        match result {
+            Err(RuleLoadError::Entries(errors)) => {
+                assert_eq!(2, errors.len());
+                assert_eq!(Some(4), errors[0].line);
+                assert_eq!(Some(5), errors[1].line);
+            },
+            _ => panic!("The rules should have been rejected"),
+        }
+
+        // Indented list, with a nested list in an entry
+        let result = Checker::from_string("
+  - id: ok
+    pattern: fine
+    emotes:
+      - 25
+  -   id: no-pattern
+");
+        match result {
+            Err(RuleLoadError::Entries(errors)) => assert_eq!(Some(6), errors[0].line),
+            _ => panic!("The rules should have been rejected"),
         }
     }
 
-    pub fn check(&self, input: &str) -> bool {
-        self.ban_list.iter().any(|&expr| expr == input)
+    #[test]
+    fn match_kinds() {
+        let checker = Checker::from_string("
+- id: nocase
+  pattern: Free Followers
+  match: exact_nocase
+- id: substring
+  pattern: bit.ly
+  match: substring
+- id: glob
+  pattern: \"buy viewers*\"
+  match: glob
+- id: regex
+  pattern: \"^cheap (views|subs) [0-9]+$\"
+  match: regex
+").unwrap();
+        assert!(checker.check("FREE FOLLOWERS").is_some());
+        assert!(checker.check("go to bit.ly/whatever").is_some());
+        assert!(checker.check("buy viewers now!!").is_some());
+        assert!(checker.check("please buy viewers").is_none());
+        assert!(checker.check("cheap subs 100").is_some());
+        assert!(checker.check("cheap subs").is_none());
+    }
+
+    #[test]
+    fn rule_normalization() {
+        let checker = Checker::from_string("
+- id: normalized
+  pattern: free followers
+- id: raw
+  pattern: \"ｃｈｅａｐ\"
+  normalize: false
+- id: no-confusables
+  pattern: cheap viewers
+  normalize:
+    confusables: false
+").unwrap();
+        assert!(checker.check("frее  fоllоwеrs").is_some());
+        assert!(checker.check("ｃｈｅａｐ").is_some());
+        assert!(checker.check("cheap").is_none());
+        assert!(checker.check("cheap\u{200B} viewers").is_some());
+        assert!(checker.check("сheap viewers").is_none());
+    }
+
+    #[test]
+    fn rule_actions() {
+        let checker = Checker::from_string("
+- id: delete
+  pattern: delete me
+  action: delete
+- id: timeout
+  pattern: time me out
+  action: timeout
+  duration: 600
+- id: default
+  pattern: ban me
+").unwrap();
+        assert_eq!(Some(Verdict { rule_id: "delete".to_owned(), action: Action::Delete, explicit_action: true }), checker.check("delete me"));
+        assert_eq!(Some(Action::Timeout(600)), checker.check("time me out").map(|v| v.action));
+        assert_eq!(Some(Action::Ban), checker.check("ban me").map(|v| v.action));
+        assert!(Checker::from_string("- { id: bad, pattern: x, action: timeout }").is_err());
+    }
+
+    #[test]
+    fn emote_rules() {
+        let checker = Checker::from_string("
+- id: banned-emote
+  emotes: [1902]
+- id: emote-spam
+  min_emotes: 3
+  min_emote_ratio: 0.9
+- id: text-and-emote
+  pattern: follow me
+  match: substring
+  min_emotes: 1
+").unwrap();
+        let kappas = Emote::parse_list("25:0-4,6-10,12-16").unwrap();
+        assert_eq!(Some("emote-spam".to_owned()), checker.check_with_emotes("Kappa Kappa Kappa", &kappas).map(|v| v.rule_id));
+        assert!(checker.check_with_emotes("Kappa Kappa Kappa and a lot of text", &kappas).is_none());
+        assert_eq!(Some("banned-emote".to_owned()), checker.check_with_emotes("hi Keepo", &Emote::parse_list("1902:3-7").unwrap()).map(|v| v.rule_id));
+        assert!(checker.check("follow me").is_none());
+        assert!(checker.check_with_emotes("follow me Kappa", &Emote::parse_list("25:10-14").unwrap()).is_some());
+    }
+
+    #[test]
+    fn room_mode_rules() {
+        let checker = Checker::from_string("
+- id: emote-spam
+  min_emotes: 3
+  skip_when: [emote_only]
+").unwrap();
+        let kappas = Emote::parse_list("25:0-4,6-10,12-16").unwrap();
+        let mut room = RoomStateTags::default();
+        assert!(checker.check_in_room("Kappa Kappa Kappa", &kappas, &room).is_some());
+        room.emote_only = Some(true);
+        assert!(checker.check_in_room("Kappa Kappa Kappa", &kappas, &room).is_none());
+        assert!(Checker::from_string("- { id: bad, pattern: x, skip_when: [disco] }").is_err());
+    }
+
+    #[test]
+    fn invalid_regex_rejected() {
+        let result = Checker::from_string("
+- id: broken
+  pattern: \"(unclosed\"
+  match: regex
+");
+        match result {
+            Err(RuleLoadError::Entries(errors)) => assert!(errors[0].message.contains("invalid regular expression")),
+            _ => panic!("The rule should have been rejected"),
+        }
     }
 }
\ No newline at end of file
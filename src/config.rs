@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::{Error, Read};
 use std::fs::File;
 use std::path::Path;
@@ -7,20 +8,171 @@ use yaml_rust::YamlLoader;
 use yaml_rust::yaml::Yaml;
 use yaml_rust::scanner::ScanError;
 
+use badges::BadgeRequirement;
+use checker::Action;
+use ladder::PunishmentLadder;
+use learning::LearningConfig;
+use lockdown::LockdownConfig;
+use raid::{IncomingRaidConfig, RaidConfig};
+use wave::WaveConfig;
+
+const DEFAULT_SERVER : &'static str = "irc.chat.twitch.tv";
+const DEFAULT_PORT : u16 = 6667;
+const DEFAULT_TLS_PORT : u16 = 6697;
+
+/// A channel to moderate. Every setting left empty falls back to the global one.
+#[derive(Clone)]
+pub struct ChannelConfig {
+    /// Name of the streamer, without the '#'
+    pub name: String,
+    /// Rules file used instead of the global one
+    pub rules: Option<String>,
+    /// Replaces the action of some rules (rule ID -> action)
+    pub actions: HashMap<String, Action>,
+    pub ladder: Option<PunishmentLadder>,
+    pub wave: Option<WaveConfig>,
+    pub raid: Option<RaidConfig>,
+    pub incoming_raids: Option<IncomingRaidConfig>,
+    pub lockdown: Option<LockdownConfig>,
+    pub learning: Option<LearningConfig>,
+    pub protect_recent_chatters: Option<i64>,
+    pub protected_badges: Option<Vec<BadgeRequirement>>,
+    pub shadow: Option<bool>,
+}
+
+impl ChannelConfig {
+    pub fn new(name: &str) -> ChannelConfig {
+        ChannelConfig {
+            name: name.to_owned(),
+            rules: None,
+            actions: HashMap::new(),
+            ladder: None,
+            wave: None,
+            raid: None,
+            incoming_raids: None,
+            lockdown: None,
+            learning: None,
+            protect_recent_chatters: None,
+            protected_badges: None,
+            shadow: None,
+        }
+    }
+
+    /// IRC name of the channel
+    pub fn irc_name(&self) -> String {
+        format!("#{}", self.name.to_lowercase())
+    }
+
+    /// Reads a channel, which is either a name or a map with the name and the overridden settings.
+    /// Settings that must not be skipped are reported in `errors`.
+    fn from_yaml(token: &Yaml, errors: &mut Vec<String>) -> Result<ChannelConfig, String> {
+        let entries = match token {
+            &Yaml::String(ref name) => return Ok(ChannelConfig::new(name)),
+            &Yaml::Hash(ref h) => h,
+            _ => return Err(format!("a channel should be a name, or a map with a 'name'")),
+        };
+
+        let mut result = match token["name"] {
+            Yaml::String(ref name) => ChannelConfig::new(name),
+            _ => return Err(format!("a channel needs a 'name'")),
+        };
+
+        for (k, v) in entries {
+            match k {
+                &Yaml::String(ref keyval) => {
+                    match keyval.as_ref() {
+                        "name" => {},
+                        "rules" => result.rules = HammerConfig::read_string(v, "rules"),
+                        "actions" => result.actions = try!(ChannelConfig::read_actions(v)),
+                        "ladder" => result.ladder = HammerConfig::read_ladder(v, &result.name, errors),
+                        "wave" => result.wave = HammerConfig::read_wave(v),
+                        "raid" => result.raid = HammerConfig::read_raid(v),
+                        "incoming_raids" => result.incoming_raids = HammerConfig::read_incoming_raids(v),
+                        "lockdown" => result.lockdown = HammerConfig::read_lockdown(v),
+                        "learning" => result.learning = HammerConfig::read_learning(v),
+                        "shadow" => result.shadow = HammerConfig::read_bool(v, "shadow"),
+                        "protect_recent_chatters" => result.protect_recent_chatters = HammerConfig::read_integer(v, "protect_recent_chatters"),
+                        "protected_badges" => result.protected_badges = HammerConfig::read_badges(v),
+                        &_ => debug!("CONFIG: Unknown key '{}' in channel {}", keyval, result.name),
+                    }
+                },
+                _ => debug!("CONFIG : Non-string key found in channel {}; skipped ({:?})", result.name, k)
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn read_actions(token: &Yaml) -> Result<HashMap<String, Action>, String> {
+        let mut result = HashMap::new();
+        if let &Yaml::Hash(ref actions) = token {
+            for (rule_id, action) in actions {
+                match (rule_id, action) {
+                    (&Yaml::String(ref rule_id), &Yaml::String(ref action)) => {
+                        result.insert(rule_id.clone(), try!(Action::parse(action)));
+                    },
+                    _ => return Err(format!("'actions' should map rule IDs to actions")),
+                }
+            }
+            Ok(result)
+        }
+        else {
+            Err(format!("'actions' should map rule IDs to actions"))
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct HammerConfig {
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub tls: Option<bool>,
+    /// Additional certificate authority to trust, for testing with a self-signed certificate
+    pub ca_file: Option<String>,
     pub username: Option<String>,
     pub oauth: Option<String>,
-    pub channel: Option<String>,
+    pub channels: Option<Vec<ChannelConfig>>,
     pub owners: Option<Vec<String>>,
+    pub rules: Option<String>,
+    pub ladder: Option<PunishmentLadder>,
+    pub wave: Option<WaveConfig>,
+    pub raid: Option<RaidConfig>,
+    /// What to do when Twitch announces a raid
+    pub incoming_raids: Option<IncomingRaidConfig>,
+    /// Room modes turned on during raids and waves
+    pub lockdown: Option<LockdownConfig>,
+    /// Rule proposals from the bans and timeouts of the moderators
+    pub learning: Option<LearningConfig>,
+    pub protect_recent_chatters: Option<i64>,
+    /// Badges protecting their owner from the hammer
+    pub protected_badges: Option<Vec<BadgeRequirement>>,
+    pub shadow: Option<bool>,
+    /// Invalid settings that cannot be skipped safely; the bot does not start with them
+    pub errors: Vec<String>,
 }
 
 impl HammerConfig {
     pub fn new() -> HammerConfig {
         HammerConfig {
+            server: None,
+            port: None,
+            tls: None,
+            ca_file: None,
             username: None,
             oauth: None,
-            channel: None,
+            channels: None,
             owners: None,
+            rules: None,
+            ladder: None,
+            wave: None,
+            raid: None,
+            incoming_raids: None,
+            lockdown: None,
+            learning: None,
+            protect_recent_chatters: None,
+            protected_badges: None,
+            shadow: None,
+            errors: Vec::new(),
         }
     }
 
@@ -47,10 +199,25 @@ impl HammerConfig {
                         match k {
                             &Yaml::String(ref keyval) => {
                                 match keyval.as_ref() {
+                                    "server" => self.server = HammerConfig::read_string(v, "server"),
+                                    "port" => self.port = HammerConfig::read_port(v),
+                                    "tls" => self.tls = HammerConfig::read_bool(v, "tls"),
+                                    "ca_file" => self.ca_file = HammerConfig::read_string(v, "ca_file"),
                                     "username" => self.username = HammerConfig::read_string(v, "username"),
                                     "oauth" => self.oauth = HammerConfig::read_string(v, "oauth"),
-                                    "channel" => self.channel = HammerConfig::read_string(v, "channel"),
+                                    "channel" => self.channels = HammerConfig::read_string(v, "channel").map(|name| vec![ChannelConfig::new(&name)]),
+                                    "channels" => self.channels = HammerConfig::read_channels(v, &mut self.errors),
                                     "owners" => self.owners = HammerConfig::read_owner_list(v),
+                                    "rules" => self.rules = HammerConfig::read_string(v, "rules"),
+                                    "ladder" => self.ladder = HammerConfig::read_ladder(v, "the global settings", &mut self.errors),
+                                    "wave" => self.wave = HammerConfig::read_wave(v),
+                                    "raid" => self.raid = HammerConfig::read_raid(v),
+                                    "incoming_raids" => self.incoming_raids = HammerConfig::read_incoming_raids(v),
+                                    "lockdown" => self.lockdown = HammerConfig::read_lockdown(v),
+                                    "learning" => self.learning = HammerConfig::read_learning(v),
+                                    "shadow" => self.shadow = HammerConfig::read_bool(v, "shadow"),
+                                    "protect_recent_chatters" => self.protect_recent_chatters = HammerConfig::read_integer(v, "protect_recent_chatters"),
+                                    "protected_badges" => self.protected_badges = HammerConfig::read_badges(v),
                                     &_ => debug!("CONFIG: Unknown key '{}'", keyval),
                                 }
                             },
@@ -83,6 +250,108 @@ impl HammerConfig {
         }
     }
 
+    fn read_channels(token: &Yaml, errors: &mut Vec<String>) -> Option<Vec<ChannelConfig>> {
+        match token {
+            &Yaml::Array(ref value) => {
+                let mut list = Vec::new();
+                for channel in value {
+                    match ChannelConfig::from_yaml(channel, errors) {
+                        Ok(channel) => list.push(channel),
+                        Err(msg) => warn!("CONFIG: An entry in the channel list is invalid and was skipped: {}", msg),
+                    }
+                }
+                Some(list)
+            },
+            _ => {
+                warn!("CONFIG: The channels entry should be a list");
+                None
+            }
+        }
+    }
+
+    fn read_badges(token: &Yaml) -> Option<Vec<BadgeRequirement>> {
+        match token {
+            &Yaml::Array(ref value) => {
+                let mut list = Vec::new();
+                for badge in value {
+                    match badge {
+                        &Yaml::String(ref text) => match BadgeRequirement::parse(text) {
+                            Ok(requirement) => list.push(requirement),
+                            Err(msg) => warn!("CONFIG: An entry in the protected badges is invalid and was skipped: {}", msg),
+                        },
+                        &_ => warn!("CONFIG: An entry in the protected badges was not a string, and was skipped ({:?})", badge),
+                    }
+                }
+                Some(list)
+            },
+            _ => {
+                warn!("CONFIG: The protected badges should be a list of badge names");
+                None
+            }
+        }
+    }
+
+    /// Skipping an invalid ladder would silently disable the escalation, so it is an error
+    fn read_ladder(token: &Yaml, owner: &str, errors: &mut Vec<String>) -> Option<PunishmentLadder> {
+        match PunishmentLadder::from_yaml(token) {
+            Ok(ladder) => Some(ladder),
+            Err(msg) => {
+                errors.push(format!("The punishment ladder of {} is invalid: {}", owner, msg));
+                None
+            }
+        }
+    }
+
+    fn read_wave(token: &Yaml) -> Option<WaveConfig> {
+        match WaveConfig::from_yaml(token) {
+            Ok(wave) => Some(wave),
+            Err(msg) => {
+                warn!("CONFIG: The wave detector configuration is invalid and was skipped: {}", msg);
+                None
+            }
+        }
+    }
+
+    fn read_raid(token: &Yaml) -> Option<RaidConfig> {
+        match RaidConfig::from_yaml(token) {
+            Ok(raid) => Some(raid),
+            Err(msg) => {
+                warn!("CONFIG: The raid detector configuration is invalid and was skipped: {}", msg);
+                None
+            }
+        }
+    }
+
+    fn read_incoming_raids(token: &Yaml) -> Option<IncomingRaidConfig> {
+        match IncomingRaidConfig::from_yaml(token) {
+            Ok(incoming_raids) => Some(incoming_raids),
+            Err(msg) => {
+                warn!("CONFIG: The incoming raids configuration is invalid and was skipped: {}", msg);
+                None
+            }
+        }
+    }
+
+    fn read_lockdown(token: &Yaml) -> Option<LockdownConfig> {
+        match LockdownConfig::from_yaml(token) {
+            Ok(lockdown) => Some(lockdown),
+            Err(msg) => {
+                warn!("CONFIG: The lockdown configuration is invalid and was skipped: {}", msg);
+                None
+            }
+        }
+    }
+
+    fn read_learning(token: &Yaml) -> Option<LearningConfig> {
+        match LearningConfig::from_yaml(token) {
+            Ok(learning) => Some(learning),
+            Err(msg) => {
+                warn!("CONFIG: The learning configuration is invalid and was skipped: {}", msg);
+                None
+            }
+        }
+    }
+
     fn read_string(token: &Yaml, val_key: &str) -> Option<String> {
         match token {
             &Yaml::String(ref value) => Some(value.clone()),
@@ -93,18 +362,58 @@ impl HammerConfig {
         }
     }
 
+    fn read_bool(token: &Yaml, val_key: &str) -> Option<bool> {
+        match token {
+            &Yaml::Boolean(value) => Some(value),
+            _ => {
+                debug!("CONFIG : Value in key {} should be a boolean but is not! ({:?})", val_key, token);
+                None
+            }
+        }
+    }
+
+    fn read_port(token: &Yaml) -> Option<u16> {
+        match HammerConfig::read_integer(token, "port") {
+            Some(value) if value > 0 && value <= u16::max_value() as i64 => Some(value as u16),
+            Some(value) => {
+                warn!("CONFIG: {} is not a valid port number", value);
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn read_integer(token: &Yaml, val_key: &str) -> Option<i64> {
+        match token {
+            &Yaml::Integer(value) => Some(value),
+            _ => {
+                debug!("CONFIG : Value in key {} should be an integer but is not! ({:?})", val_key, token);
+                None
+            }
+        }
+    }
+
+    /// TLS is used unless it is explicitly disabled
+    pub fn use_tls(&self) -> bool {
+        self.tls.unwrap_or(true)
+    }
+
     pub fn to_irc_config(&self) -> IrcConfig {
-        // Copy the values over
+        let default_port = if self.use_tls() { DEFAULT_TLS_PORT } else { DEFAULT_PORT };
+
+        // Copy the values over.
+        // TLS is handled by our own connection, so the IRC library must not know about it.
         let mut result = IrcConfig {
-            server: Some(format!("irc.chat.twitch.tv")),
-            port: Some(6667),
+            server: Some(self.server.clone().unwrap_or(DEFAULT_SERVER.to_owned())),
+            port: Some(self.port.unwrap_or(default_port)),
+            use_ssl: Some(false),
             nickname: self.username.clone(),
             password: self.oauth.clone(),
             .. Default::default()
         };
 
-        if let Some(ref channel_name) = self.channel {
-            result.channels = Some(vec![format!("#{}", channel_name.to_lowercase())]);
+        if let Some(ref channels) = self.channels {
+            result.channels = Some(channels.iter().map(ChannelConfig::irc_name).collect());
         }
 
         if let Some(ref owners_names) = self.owners {
@@ -115,8 +424,9 @@ impl HammerConfig {
     }
 
     pub fn validate(&self) -> bool {
-        self.channel.is_some() && 
+        self.channels.as_ref().map_or(false, |channels| !channels.is_empty()) &&
         self.oauth.is_some() &&
-        self.username.is_some()
+        self.username.is_some() &&
+        self.rules.is_some()
     }
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+/// An emote in a chat message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Emote {
+    pub id: String,
+    /// Position of the first character of the emote name in the message (in characters, not bytes)
+    pub start: usize,
+    /// Position of the last character of the emote name (included)
+    pub end: usize,
+}
+
+impl Emote {
+    /// Parses the value of the "emotes" tag, like "25:0-4,12-16/1902:6-10".
+    /// Every use of an emote gives one entry, and the entries are sorted by position.
+    pub fn parse_list(text: &str) -> Result<Vec<Emote>, String> {
+        let mut result = Vec::new();
+        for emote in text.split('/').filter(|e| !e.is_empty()) {
+            let mut parts = emote.splitn(2, ':');
+            let id = parts.next().unwrap_or("");
+            let ranges = match parts.next() {
+                Some(ranges) if !id.is_empty() => ranges,
+                _ => return Err(format!("invalid emote '{}'", emote)),
+            };
+
+            for range in ranges.split(',') {
+                let mut bounds = range.splitn(2, '-');
+                let start = bounds.next().and_then(|b| usize::from_str(b).ok());
+                let end = bounds.next().and_then(|b| usize::from_str(b).ok());
+                match (start, end) {
+                    (Some(start), Some(end)) if start <= end => result.push(Emote { id: id.to_owned(), start: start, end: end }),
+                    _ => return Err(format!("invalid range '{}' for emote {}", range, id)),
+                }
+            }
+        }
+
+        result.sort_by_key(|emote| emote.start);
+        Ok(result)
+    }
+
+    /// Number of characters of the emote name
+    pub fn char_count(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+/// Share of the message (not counting whitespace) made of emotes, between 0 and 1
+pub fn emote_ratio(text: &str, emotes: &[Emote]) -> f64 {
+    let visible = text.chars().filter(|c| !c.is_whitespace()).count();
+    if visible == 0 {
+        return 0.0;
+    }
+    let emote_chars: usize = emotes.iter().map(Emote::char_count).sum();
+    (emote_chars as f64 / visible as f64).min(1.0)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_emotes() {
+        let emotes = Emote::parse_list("25:0-4,12-16/1902:6-10").unwrap();
+        assert_eq!(3, emotes.len());
+        assert_eq!(Emote { id: "1902".to_owned(), start: 6, end: 10 }, emotes[1]);
+        assert_eq!("25", emotes[2].id);
+        assert!(Emote::parse_list("").unwrap().is_empty());
+        assert!(Emote::parse_list("25:4-0").is_err());
+        assert!(Emote::parse_list("25").is_err());
+    }
+
+    #[test]
+    fn ratio() {
+        let emotes = Emote::parse_list("25:0-4,6-10").unwrap();
+        assert_eq!(1.0, emote_ratio("Kappa Kappa", &emotes));
+        assert_eq!(0.5, emote_ratio("Kappa Kappa 0123456789", &emotes));
+        assert_eq!(0.0, emote_ratio("hello", &[]));
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use chat::Chat;
+use checker::Checker;
+use config::HammerConfig;
+use replay::raw_line;
+
+/// Room ID given to every channel of the fake server
+const FAKE_ROOM_ID : u64 = 1;
+
+/// Pause between two lines of a script, so that the bot sees them in order
+const SCRIPT_LINE_DELAY_MS : u64 = 20;
+/// How long the bot gets to answer after the last line of a script
+const SCRIPT_END_DELAY_MS : u64 = 2000;
+
+/// Used to give unique IDs to the fake messages and users
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// A tiny Twitch IRC server, good enough to run the bot against it end to end.
+/// It accepts any login, makes the bot a moderator of every channel, acknowledges every capability,
+/// answers bans and timeouts the way Twitch does, and records every line the bot sends.
+pub struct FakeTmi {
+    port: u16,
+    /// Lines received from the bot, without the line terminator
+    received: Arc<Mutex<Vec<String>>>,
+    /// The bot currently connected, if any
+    client: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl FakeTmi {
+    /// Starts listening on localhost. With port 0, the system picks a free port.
+    pub fn start(port: u16) -> Result<FakeTmi> {
+        let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+        let result = FakeTmi {
+            port: try!(listener.local_addr()).port(),
+            received: Arc::new(Mutex::new(Vec::new())),
+            client: Arc::new(Mutex::new(None)),
+        };
+
+        let received = result.received.clone();
+        let client = result.client.clone();
+        spawn(move || {
+            // One client at a time: a reconnection replaces the previous one
+            for stream in listener.incoming() {
+                match stream.and_then(|s| s.try_clone().map(|c| (s, c))) {
+                    Ok((stream, writer)) => {
+                        info!("FAKE TMI: client connected");
+                        *client.lock().unwrap() = Some(writer);
+                        FakeTmi::serve(stream, &received, &client);
+                        info!("FAKE TMI: client disconnected");
+                    },
+                    Err(err) => warn!("FAKE TMI: could not accept a client: {}", err),
+                }
+            }
+        });
+
+        Ok(result)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Every line received from the bot so far
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Waits until the bot sends a line matching the predicate. Returns false on timeout.
+    pub fn wait_for<F: Fn(&str) -> bool>(&self, predicate: F, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.received.lock().unwrap().iter().any(|line| predicate(line)) {
+                return true;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Sends a raw line to the bot
+    pub fn send(&self, line: &str) {
+        FakeTmi::send_to(&self.client, line);
+    }
+
+    /// Sends a chat message from a user, with the tags Twitch would add
+    pub fn send_privmsg(&self, channel: &str, nickname: &str, text: &str) {
+        let user_id = next_id();
+        let badges = if channel.trim_left_matches('#') == nickname { "broadcaster/1" } else { "" };
+        self.send(&format!("@badges={badges};color=;display-name={nick};emotes=;id={msg_id};mod=0;room-id={room_id};subscriber=0;turbo=0;user-id={user_id};user-type= :{nick}!{nick}@{nick}.tmi.twitch.tv PRIVMSG {channel} :{text}",
+            badges = badges, nick = nickname, msg_id = FakeTmi::message_id(next_id()), room_id = FAKE_ROOM_ID,
+            user_id = user_id, channel = channel, text = text));
+    }
+
+    /// Message IDs look like UUIDs
+    fn message_id(number: usize) -> String {
+        format!("00000000-0000-0000-0000-{:012}", number)
+    }
+
+    fn send_to(client: &Mutex<Option<TcpStream>>, line: &str) {
+        debug!("FAKE TMI: sending '{}'", line);
+        if let Some(ref mut stream) = *client.lock().unwrap() {
+            if let Err(err) = stream.write_all(format!("{}\r\n", line).as_bytes()) {
+                warn!("FAKE TMI: could not send '{}': {}", line, err);
+            }
+        }
+        else {
+            warn!("FAKE TMI: no client to send '{}' to", line);
+        }
+    }
+
+    /// Sends the result of a ban (no duration) or a timeout
+    fn clearchat_to(client: &Mutex<Option<TcpStream>>, channel: &str, nickname: &str, duration: Option<u32>) {
+        let duration_tag = match duration {
+            Some(seconds) => format!("ban-duration={};", seconds),
+            None => String::new(),
+        };
+        FakeTmi::send_to(client, &format!("@{}room-id={};target-user-id={} :tmi.twitch.tv CLEARCHAT {} :{}",
+            duration_tag, FAKE_ROOM_ID, next_id(), channel, nickname));
+    }
+
+    /// Tells the bot that it moderates the channel
+    fn userstate_to(client: &Mutex<Option<TcpStream>>, channel: &str, nickname: &str) {
+        FakeTmi::send_to(client, &format!("@badge-info=;badges=moderator/1;color=;display-name={};emote-sets=0;mod=1;subscriber=0;user-type=mod :tmi.twitch.tv USERSTATE {}",
+            nickname, channel));
+    }
+
+    /// Sends the room state, as received when joining a channel
+    fn roomstate_to(client: &Mutex<Option<TcpStream>>, channel: &str) {
+        FakeTmi::send_to(client, &format!("@emote-only=0;followers-only=-1;r9k=0;room-id={};slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE {}",
+            FAKE_ROOM_ID, channel));
+    }
+
+    /// Confirms a room mode change, with a NOTICE and the part of the room state that changed
+    fn room_mode_to(client: &Mutex<Option<TcpStream>>, channel: &str, notice_tags: &str, roomstate_tag: &str) {
+        FakeTmi::send_to(client, &format!("@{} :tmi.twitch.tv NOTICE {} :The room mode changed.", notice_tags, channel));
+        FakeTmi::send_to(client, &format!("@{};room-id={} :tmi.twitch.tv ROOMSTATE {}", roomstate_tag, FAKE_ROOM_ID, channel));
+    }
+
+    /// Reads the lines of a client and answers them until it disconnects
+    fn serve(stream: TcpStream, received: &Mutex<Vec<String>>, client: &Mutex<Option<TcpStream>>) {
+        let mut nickname = String::from("unknown");
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line.trim_right_matches('\r').to_owned(),
+                Err(_) => break,
+            };
+            debug!("FAKE TMI: received '{}'", line);
+            received.lock().unwrap().push(line.clone());
+
+            let mut words = line.splitn(2, ' ');
+            let command = words.next().unwrap_or("");
+            let params = words.next().unwrap_or("");
+            match command {
+                "CAP" if params.starts_with("REQ ") => {
+                    let caps = params["REQ ".len()..].trim_left_matches(':');
+                    FakeTmi::send_to(client, &format!(":tmi.twitch.tv CAP * ACK :{}", caps));
+                },
+                "NICK" => {
+                    nickname = params.trim_left_matches(':').to_lowercase();
+                    FakeTmi::send_to(client, &format!(":tmi.twitch.tv 001 {} :Welcome, GLHF!", nickname));
+                    FakeTmi::send_to(client, &format!(":tmi.twitch.tv 376 {} :>", nickname));
+                    FakeTmi::send_to(client, &format!("@badge-info=;badges=;color=;display-name={};emote-sets=0;user-id={};user-type= :tmi.twitch.tv GLOBALUSERSTATE",
+                        nickname, next_id()));
+                },
+                "PING" => FakeTmi::send_to(client, &format!(":tmi.twitch.tv PONG {}", params)),
+                "JOIN" => {
+                    for channel in params.trim_left_matches(':').split(',') {
+                        FakeTmi::send_to(client, &format!(":{nick}!{nick}@{nick}.tmi.twitch.tv JOIN {channel}", nick = nickname, channel = channel));
+                        FakeTmi::userstate_to(client, channel, &nickname);
+                        FakeTmi::roomstate_to(client, channel);
+                    }
+                },
+                "PRIVMSG" => {
+                    let mut parts = params.splitn(2, " :");
+                    let channel = parts.next().unwrap_or("");
+                    let text = parts.next().unwrap_or("");
+                    FakeTmi::userstate_to(client, channel, &nickname);
+                    FakeTmi::answer_command(client, channel, text);
+                },
+                _ => {},
+            }
+        }
+        *client.lock().unwrap() = None;
+    }
+
+    /// Answers the moderation commands sent by the bot
+    fn answer_command(client: &Mutex<Option<TcpStream>>, channel: &str, text: &str) {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match (words.get(0).cloned(), words.get(1).cloned()) {
+            (Some("/ban"), Some(target)) => {
+                FakeTmi::clearchat_to(client, channel, target, None);
+                FakeTmi::send_to(client, &format!("@msg-id=ban_success;target-user={} :tmi.twitch.tv NOTICE {} :{} is now banned from this channel.",
+                    target, channel, target));
+            },
+            (Some("/timeout"), Some(target)) => {
+                let duration = words.get(2).and_then(|d| d.parse().ok()).unwrap_or(600);
+                FakeTmi::clearchat_to(client, channel, target, Some(duration));
+                FakeTmi::send_to(client, &format!("@ban-duration={};msg-id=timeout_success;target-user={} :tmi.twitch.tv NOTICE {} :{} has been timed out for {} seconds.",
+                    duration, target, channel, target, duration));
+            },
+            (Some("/delete"), Some(_)) => {
+                FakeTmi::send_to(client, &format!("@msg-id=delete_message_success :tmi.twitch.tv NOTICE {} :The message was deleted.", channel));
+            },
+            (Some("/slow"), seconds) => {
+                let seconds = seconds.unwrap_or("30");
+                FakeTmi::room_mode_to(client, channel, &format!("slow-duration={};msg-id=slow_on", seconds), &format!("slow={}", seconds));
+            },
+            (Some("/slowoff"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=slow_off", "slow=0"),
+            (Some("/followers"), minutes) => {
+                let minutes = minutes.unwrap_or("0").trim_right_matches('m');
+                FakeTmi::room_mode_to(client, channel, "msg-id=followers_on", &format!("followers-only={}", minutes));
+            },
+            (Some("/followersoff"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=followers_off", "followers-only=-1"),
+            (Some("/emoteonly"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=emote_only_on", "emote-only=1"),
+            (Some("/emoteonlyoff"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=emote_only_off", "emote-only=0"),
+            (Some("/subscribers"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=subs_on", "subs-only=1"),
+            (Some("/subscribersoff"), _) => FakeTmi::room_mode_to(client, channel, "msg-id=subs_off", "subs-only=0"),
+            _ => {},
+        }
+    }
+}
+
+/// Runs the bot against a fake server, plays a script, and prints what the bot sent.
+/// The script has one line per message: "<nickname> text" for a chat message in the first channel, anything else
+/// is sent as a raw IRC line. Blank lines and "//" comments are skipped, like in replay files.
+pub fn run<P: AsRef<Path>>(conf: &HammerConfig, checker: Checker, channel_checkers: HashMap<String, Checker>, script: P) -> Result<()> {
+    let file = BufReader::new(try!(File::open(script)));
+    let mut lines = Vec::new();
+    for line in file.lines() {
+        let line = try!(line);
+        if let Some(raw) = raw_line(&line) {
+            lines.push(raw.to_owned());
+        }
+    }
+
+    let server = try!(FakeTmi::start(0));
+    let mut bot_conf = conf.clone();
+    bot_conf.server = Some("127.0.0.1".to_owned());
+    bot_conf.port = Some(server.port());
+    bot_conf.tls = Some(false);
+    // Chat messages of the script go to the first channel
+    let channel = bot_conf.channels.as_ref().unwrap()[0].irc_name();
+    println!("Fake TMI server listening on port {}", server.port());
+
+    spawn(move || {
+        let mut chat = Chat::new(&bot_conf, checker, channel_checkers);
+        chat.run();
+    });
+
+    if !server.wait_for(|line| line.starts_with("JOIN "), Duration::from_secs(10)) {
+        println!("The bot did not join the channel.");
+    }
+    else {
+        for line in lines {
+            if line.starts_with('<') {
+                if let Some(end) = line.find('>') {
+                    server.send_privmsg(&channel, &line[1..end], line[end + 1..].trim());
+                    sleep(Duration::from_millis(SCRIPT_LINE_DELAY_MS));
+                    continue;
+                }
+            }
+            server.send(&line);
+            sleep(Duration::from_millis(SCRIPT_LINE_DELAY_MS));
+        }
+        sleep(Duration::from_millis(SCRIPT_END_DELAY_MS));
+    }
+
+    for line in server.received() {
+        if line.starts_with("PASS ") {
+            println!("> PASS ***");
+        }
+        else {
+            println!("> {}", line);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use checker::Action;
+    use config::ChannelConfig;
+
+    #[test]
+    fn bot_bans_through_fake_server() {
+        let server = FakeTmi::start(0).unwrap();
+        let mut conf = HammerConfig::new();
+        conf.server = Some("127.0.0.1".to_owned());
+        conf.port = Some(server.port());
+        conf.tls = Some(false);
+        conf.username = Some("hammer_bot".to_owned());
+        conf.oauth = Some("oauth:test".to_owned());
+        let mut other = ChannelConfig::new("other");
+        other.actions.insert("spam".to_owned(), Action::Timeout(60));
+        conf.channels = Some(vec![ChannelConfig::new("streamer"), other]);
+        let checker = Checker::from_string("- id: spam\n  pattern: buy followers\n  match: substring\n").unwrap();
+        spawn(move || Chat::new(&conf, checker, HashMap::new()).run());
+
+        assert!(server.wait_for(|line| line == "JOIN #other", Duration::from_secs(5)));
+        assert!(server.received().iter().any(|line| line.starts_with("CAP REQ")));
+        server.send_privmsg("#streamer", "streamer", ":hammer on");
+        // Hammer mode is only on in the first channel
+        server.send_privmsg("#other", "spambot", "buy followers now");
+        server.send_privmsg("#streamer", "spambot", "buy followers now");
+        assert!(server.wait_for(|line| line == "PRIVMSG #streamer :/ban spambot", Duration::from_secs(5)));
+        server.send_privmsg("#other", "other", ":hammer on");
+        server.send_privmsg("#other", "spambot2", "buy followers now");
+        assert!(server.wait_for(|line| line == "PRIVMSG #other :/timeout spambot2 60", Duration::from_secs(5)));
+        // The message sent before the hammer mode was on in the other channel was not punished
+        assert!(!server.received().iter().any(|line| line.starts_with("PRIVMSG #other :/") && line.ends_with(" spambot")));
+    }
+}
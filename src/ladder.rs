@@ -0,0 +1,88 @@
+use yaml_rust::yaml::Yaml;
+
+use checker::Action;
+
+/// Escalating punishments for repeat offenders.
+/// Each rule match gives the user a strike, and the number of recent strikes
+/// decides how hard the user is punished.
+#[derive(Debug, Clone)]
+pub struct PunishmentLadder {
+    /// Action for the first strike, second strike etc. The last one is used for any strike after that.
+    pub steps: Vec<Action>,
+    /// Number of seconds after which a strike is forgotten
+    pub strike_lifetime: i64,
+}
+
+impl PunishmentLadder {
+    /// Reads the ladder from the configuration:
+    ///
+    /// ```yaml
+    /// ladder:
+    ///   strike_lifetime: 3600
+    ///   steps: [delete, timeout 60, timeout 600, ban]
+    /// ```
+    pub fn from_yaml(token: &Yaml) -> Result<PunishmentLadder, String> {
+        let steps = match token["steps"] {
+            Yaml::Array(ref entries) if !entries.is_empty() => {
+                let mut steps = Vec::new();
+                for entry in entries {
+                    match entry {
+                        &Yaml::String(ref value) => steps.push(try!(Action::parse(value))),
+                        _ => return Err(format!("the ladder steps should be strings ({:?})", entry)),
+                    }
+                }
+                steps
+            },
+            _ => return Err(format!("the ladder needs a non-empty 'steps' list")),
+        };
+
+        let strike_lifetime = match token["strike_lifetime"] {
+            Yaml::Integer(value) if value > 0 => value,
+            _ => return Err(format!("the ladder needs a positive 'strike_lifetime' (in seconds)")),
+        };
+
+        Ok(PunishmentLadder {
+            steps: steps,
+            strike_lifetime: strike_lifetime,
+        })
+    }
+
+    /// Returns the action for a user having the given number of active strikes
+    /// (including the one being handed out).
+    /// A rule that chose its own action keeps it if it is harsher than the ladder step;
+    /// the others (`None`) get the ladder step.
+    pub fn escalate(&self, strikes: usize, rule_action: Option<Action>) -> Action {
+        let index = if strikes == 0 { 0 } else { strikes - 1 };
+        let step = self.steps[if index < self.steps.len() { index } else { self.steps.len() - 1 }];
+        match rule_action {
+            Some(action) if action.severity() > step.severity() => action,
+            _ => step,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn ladder_escalation() {
+        let doc = YamlLoader::load_from_str("
+strike_lifetime: 3600
+steps: [delete, timeout 60, timeout 600, ban]
+").unwrap();
+        let ladder = PunishmentLadder::from_yaml(&doc[0]).unwrap();
+        assert_eq!(Action::Delete, ladder.escalate(1, Some(Action::Delete)));
+        assert_eq!(Action::Timeout(60), ladder.escalate(2, Some(Action::Delete)));
+        assert_eq!(Action::Timeout(600), ladder.escalate(3, Some(Action::Timeout(60))));
+        assert_eq!(Action::Ban, ladder.escalate(4, Some(Action::Delete)));
+        assert_eq!(Action::Ban, ladder.escalate(9, Some(Action::Delete)));
+        // A rule that chose to ban is not softened by the ladder
+        assert_eq!(Action::Ban, ladder.escalate(1, Some(Action::Ban)));
+        // The rules without an action follow the ladder
+        assert_eq!(Action::Delete, ladder.escalate(1, None));
+        assert_eq!(Action::Timeout(600), ladder.escalate(3, None));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::escape;
+use yaml_rust::YamlLoader;
+use yaml_rust::yaml::Yaml;
+
+use checker::Action;
+use normalize::Normalization;
+
+/// Number of examples kept to look for patterns; older ones are forgotten
+const MAX_EXAMPLES : usize = 200;
+/// Shortest and longest word sequences compared between the examples
+const MIN_WORDS : usize = 3;
+const MAX_WORDS : usize = 8;
+/// Shorter patterns would match too many innocent messages
+const MIN_PATTERN_LENGTH : usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct LearningConfig {
+    /// Number of users punished for similar messages before a rule is proposed
+    pub min_users: usize,
+    /// Number of messages remembered for each user
+    pub messages: usize,
+    /// File where the proposals are written for review. "{channel}" is replaced by the channel name.
+    pub file: Option<String>,
+}
+
+impl LearningConfig {
+    pub fn from_yaml(token: &Yaml) -> Result<LearningConfig, String> {
+        let min_users = match token["min_users"] {
+            Yaml::Integer(value) if value > 1 => value as usize,
+            Yaml::BadValue => 3,
+            _ => return Err(format!("'min_users' should be a number greater than 1")),
+        };
+
+        let messages = match token["messages"] {
+            Yaml::Integer(value) if value > 0 => value as usize,
+            Yaml::BadValue => 5,
+            _ => return Err(format!("'messages' should be a positive number")),
+        };
+
+        let file = match token["file"] {
+            Yaml::String(ref value) => Some(value.clone()),
+            Yaml::BadValue => None,
+            _ => return Err(format!("'file' should be a file name")),
+        };
+
+        Ok(LearningConfig {
+            min_users: min_users,
+            messages: messages,
+            file: file,
+        })
+    }
+}
+
+/// The last messages of a user punished by a human moderator
+struct Example {
+    nickname: String,
+    action: Action,
+    /// Normalized and in lowercase
+    messages: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    /// Accepted by a moderator (nickname)
+    Accepted(String),
+    Rejected,
+}
+
+/// A rule suggested from the messages of punished users
+#[derive(Debug, Clone)]
+pub struct Proposal {
+    pub id: usize,
+    /// The text shared by the messages (normalized, in lowercase)
+    pub text: String,
+    /// True if the whole messages were equal, false if they only contained the text
+    pub whole_message: bool,
+    /// Harshest punishment the moderators gave for it
+    pub action: Action,
+    /// Users punished for messages with this text
+    pub nicknames: Vec<String>,
+    pub status: ProposalStatus,
+}
+
+impl Proposal {
+    /// The rule, as an entry of the rules file
+    pub fn to_yaml(&self) -> String {
+        let (kind, pattern) = if self.whole_message {
+            ("exact_nocase", self.text.clone())
+        }
+        else {
+            ("regex", format!("(?i){}", escape(&self.text)))
+        };
+        let mut result = format!("- id: learned-{}\n  pattern: \"{}\"\n  match: {}\n", self.id, yaml_escape(&pattern), kind);
+        match self.action {
+            Action::Timeout(duration) => result.push_str(&format!("  action: timeout\n  duration: {}\n", duration)),
+            action => result.push_str(&format!("  action: {}\n", action)),
+        }
+        result.push_str(&format!("  description: \"Learned from the moderators, who punished {} users for it\"\n", self.nicknames.len()));
+        if let ProposalStatus::Accepted(ref nickname) = self.status {
+            result.push_str(&format!("  added_by: {}\n", nickname));
+        }
+        result
+    }
+}
+
+/// Remembers what the moderators punished, and proposes rules for the messages they keep punishing
+pub struct Learner {
+    config: LearningConfig,
+    examples: Vec<Example>,
+    proposals: Vec<Proposal>,
+    /// Number of the next proposal, which may come after rejected proposals that were forgotten
+    next_id: usize,
+}
+
+impl Learner {
+    pub fn new(config: LearningConfig) -> Learner {
+        Learner {
+            config: config,
+            examples: Vec::new(),
+            proposals: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn config(&self) -> &LearningConfig {
+        &self.config
+    }
+
+    pub fn proposals(&self) -> &[Proposal] {
+        &self.proposals
+    }
+
+    /// Records the last messages of a user punished by a moderator.
+    /// Returns the IDs of the proposals this example led to.
+    pub fn add_example(&mut self, nickname: &str, action: Action, messages: &[String]) -> Vec<usize> {
+        let normalization = Normalization::all();
+        let messages: Vec<String> = messages.iter()
+            .map(|message| normalization.apply(message).to_lowercase())
+            .filter(|message| !message.is_empty())
+            .collect();
+        if messages.is_empty() {
+            return Vec::new();
+        }
+
+        self.examples.push(Example {
+            nickname: nickname.to_owned(),
+            action: action,
+            messages: messages,
+        });
+        if self.examples.len() > MAX_EXAMPLES {
+            self.examples.remove(0);
+        }
+
+        let mut result = Vec::new();
+        for (text, whole_message, nicknames) in self.find_patterns() {
+            let known = self.proposals.iter().any(|proposal| proposal.text.contains(text.as_str()) || text.contains(proposal.text.as_str()));
+            if known {
+                continue;
+            }
+
+            let action = self.examples.iter()
+                .filter(|example| nicknames.contains(&example.nickname))
+                .map(|example| example.action)
+                .max_by_key(Action::severity)
+                .unwrap_or(action);
+            let id = self.next_id;
+            self.next_id += 1;
+            self.proposals.push(Proposal {
+                id: id,
+                text: text,
+                whole_message: whole_message,
+                action: action,
+                nicknames: nicknames,
+                status: ProposalStatus::Pending,
+            });
+            result.push(id);
+        }
+        result
+    }
+
+    /// Finds the messages, then the word sequences, sent by enough different users.
+    /// Returns (text, whole message, nicknames), the most specific ones first.
+    fn find_patterns(&self) -> Vec<(String, bool, Vec<String>)> {
+        let mut whole: HashMap<&str, HashSet<&str>> = HashMap::new();
+        let mut sequences: HashMap<String, HashSet<&str>> = HashMap::new();
+        for example in &self.examples {
+            for message in &example.messages {
+                whole.entry(message.as_str()).or_insert(HashSet::new()).insert(example.nickname.as_str());
+                let words: Vec<&str> = message.split_whitespace().collect();
+                for length in MIN_WORDS..MAX_WORDS + 1 {
+                    for sequence in words.windows(length) {
+                        sequences.entry(sequence.join(" ")).or_insert(HashSet::new()).insert(example.nickname.as_str());
+                    }
+                }
+            }
+        }
+
+        let shared = |users: &HashSet<&str>| {
+            let mut nicknames: Vec<String> = users.iter().map(|n| n.to_string()).collect();
+            nicknames.sort();
+            nicknames
+        };
+
+        let mut result: Vec<(String, bool, Vec<String>)> = Vec::new();
+        let mut candidates: Vec<(&str, &HashSet<&str>)> = whole.iter()
+            .filter(|&(text, users)| users.len() >= self.config.min_users && text.len() >= MIN_PATTERN_LENGTH)
+            .map(|(text, users)| (*text, users))
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(b.0));
+        for (text, users) in candidates {
+            result.push((text.to_owned(), true, shared(users)));
+        }
+
+        let mut candidates: Vec<(&String, &HashSet<&str>)> = sequences.iter()
+            .filter(|&(text, users)| users.len() >= self.config.min_users && text.len() >= MIN_PATTERN_LENGTH)
+            .collect();
+        // Longest sequences first, so that their parts are not proposed as well
+        candidates.sort_by(|a, b| b.0.split(' ').count().cmp(&a.0.split(' ').count()).then(a.0.cmp(b.0)));
+        for (text, users) in candidates {
+            if !result.iter().any(|&(ref chosen, _, _)| chosen.contains(text.as_str())) {
+                result.push((text.clone(), false, shared(users)));
+            }
+        }
+        result
+    }
+
+    pub fn accept(&mut self, id: usize, nickname: &str) -> Result<&Proposal, String> {
+        let proposal = try!(self.find_pending(id));
+        proposal.status = ProposalStatus::Accepted(nickname.to_owned());
+        Ok(proposal)
+    }
+
+    /// Puts an accepted proposal back in review
+    pub fn unaccept(&mut self, id: usize) {
+        if let Some(proposal) = self.proposals.iter_mut().find(|proposal| proposal.id == id) {
+            proposal.status = ProposalStatus::Pending;
+        }
+    }
+
+    pub fn reject(&mut self, id: usize) -> Result<&Proposal, String> {
+        let proposal = try!(self.find_pending(id));
+        proposal.status = ProposalStatus::Rejected;
+        Ok(proposal)
+    }
+
+    fn find_pending(&mut self, id: usize) -> Result<&mut Proposal, String> {
+        match self.proposals.iter_mut().find(|proposal| proposal.id == id) {
+            Some(ref proposal) if proposal.status != ProposalStatus::Pending => Err(format!("Rule proposal #{} was already reviewed.", id)),
+            Some(proposal) => Ok(proposal),
+            None => Err(format!("There is no rule proposal #{}.", id)),
+        }
+    }
+
+    /// The accepted proposals, as a rules file
+    pub fn accepted_rules(&self) -> String {
+        self.proposals.iter()
+            .filter(|proposal| match proposal.status { ProposalStatus::Accepted(_) => true, _ => false })
+            .map(Proposal::to_yaml)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// The proposals, with comments for the moderators reviewing them. Only the number of the rejected ones is kept.
+    pub fn review_file(&self, channel: &str) -> String {
+        let mut result = format!("# Rules proposed for {} from the bans and timeouts of the moderators.\n\
+            # Accept them in the chat with \":hammer accept <number>\", or copy them to your rules file.\n", channel);
+        for proposal in &self.proposals {
+            let status = match proposal.status {
+                ProposalStatus::Pending => format!("pending"),
+                ProposalStatus::Accepted(ref nickname) => format!("accepted by {}", nickname),
+                ProposalStatus::Rejected => {
+                    result.push_str(&format!("\n# Proposal #{} (rejected)\n", proposal.id));
+                    continue;
+                },
+            };
+            result.push_str(&format!("\n# Proposal #{} ({}), from the messages of {}\n", proposal.id, status, proposal.nicknames.join(", ")));
+            result.push_str(&proposal.to_yaml());
+        }
+        result
+    }
+
+    /// Reads back a file written by `review_file`, so that the proposals and their numbers survive a restart
+    pub fn load_review_file(&mut self, content: &str) -> Result<(), String> {
+        // The nicknames and the rejected proposals are only in the comments
+        let mut nicknames = HashMap::new();
+        const HEADER: &'static str = "# Proposal #";
+        const USERS: &'static str = ", from the messages of ";
+        for line in content.lines().filter(|line| line.starts_with(HEADER)) {
+            let rest = &line[HEADER.len()..];
+            let id = match rest.split(' ').next().and_then(|id| id.parse::<usize>().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+            if id >= self.next_id {
+                self.next_id = id + 1;
+            }
+            if let Some(pos) = rest.find(USERS) {
+                nicknames.insert(id, rest[pos + USERS.len()..].split(", ").map(str::to_owned).collect::<Vec<String>>());
+            }
+        }
+
+        let docs = try!(YamlLoader::load_from_str(content).map_err(|err| err.to_string()));
+        let entries = match docs.get(0) {
+            Some(&Yaml::Array(ref entries)) => entries.clone(),
+            Some(&Yaml::Null) | None => Vec::new(),
+            Some(_) => return Err(format!("the proposals should be a list")),
+        };
+        for entry in entries {
+            let id = match entry["id"].as_str().and_then(|id| id.trim_left_matches("learned-").parse::<usize>().ok()) {
+                Some(id) => id,
+                None => return Err(format!("invalid proposal ID {:?}", entry["id"])),
+            };
+            let pattern = entry["pattern"].as_str().unwrap_or("");
+            let (text, whole_message) = match entry["match"].as_str() {
+                Some("exact_nocase") => (pattern.to_owned(), true),
+                Some("regex") => (unescape(pattern.trim_left_matches("(?i)")), false),
+                _ => return Err(format!("proposal #{} has an unknown match kind", id)),
+            };
+            let action = try!(Action::from_name(entry["action"].as_str().unwrap_or(""), entry["duration"].as_i64().map(|d| d as u32))
+                .map_err(|err| format!("proposal #{}: {}", id, err)));
+            let status = match entry["added_by"].as_str() {
+                Some(nickname) => ProposalStatus::Accepted(nickname.to_owned()),
+                None => ProposalStatus::Pending,
+            };
+
+            if id >= self.next_id {
+                self.next_id = id + 1;
+            }
+            self.proposals.push(Proposal {
+                id: id,
+                text: text,
+                whole_message: whole_message,
+                action: action,
+                nicknames: nicknames.remove(&id).unwrap_or(Vec::new()),
+                status: status,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Reverts `regex::escape`, which puts a backslash before the special characters
+fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Escapes a value to write it between double quotes in YAML
+fn yaml_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use checker::Checker;
+
+    fn learner() -> Learner {
+        Learner::new(LearningConfig { min_users: 3, messages: 5, file: None })
+    }
+
+    #[test]
+    fn proposes_shared_sequences() {
+        let mut learner = learner();
+        assert!(learner.add_example("bot1", Action::Timeout(600), &["Hi! Buy cheap followers at bigfollows".to_owned()]).is_empty());
+        assert!(learner.add_example("bot2", Action::Ban, &["buy cheap  followers at BIGFOLLOWS now".to_owned(), "hello".to_owned()]).is_empty());
+        assert_eq!(vec![1], learner.add_example("bot3", Action::Timeout(60), &["you should buy cheap followers at bigfollows today".to_owned()]));
+        let proposal = &learner.proposals()[0];
+        assert_eq!("buy cheap followers at bigfollows", proposal.text);
+        assert!(!proposal.whole_message);
+        assert_eq!(Action::Ban, proposal.action);
+        assert_eq!(vec!["bot1", "bot2", "bot3"], proposal.nicknames);
+
+        // The parts of a proposal are not proposed again
+        assert!(learner.add_example("bot4", Action::Ban, &["buy cheap followers at another place".to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn proposes_whole_messages() {
+        let mut learner = learner();
+        for nickname in &["bot1", "bot2", "bot3"] {
+            learner.add_example(nickname, Action::Ban, &["Wanna become famous?".to_owned()]);
+        }
+        assert_eq!(1, learner.proposals().len());
+        assert!(learner.proposals()[0].whole_message);
+        assert_eq!("wanna become famous?", learner.proposals()[0].text);
+    }
+
+    #[test]
+    fn accepted_rules_load() {
+        let mut learner = learner();
+        for nickname in &["bot1", "bot2", "bot3"] {
+            learner.add_example(nickname, Action::Timeout(600), &[format!("{} says: \"free (viewers)\" \\o/ here", nickname)]);
+        }
+        assert_eq!(1, learner.proposals().len());
+        assert!(learner.reject(2).is_err());
+        assert!(learner.accept(1, "a_mod").is_ok());
+        assert!(learner.accept(1, "a_mod").is_err());
+
+        let checker = Checker::from_string(&learner.accepted_rules()).unwrap();
+        let verdict = checker.check("Someone says: \"FREE (viewers)\" \\o/ here!").unwrap();
+        assert_eq!("learned-1", verdict.rule_id);
+        assert_eq!(Action::Timeout(600), verdict.action);
+        assert!(learner.review_file("#streamer").contains("accepted by a_mod"));
+    }
+
+    #[test]
+    fn review_file_reloads() {
+        let mut learner = learner();
+        for nickname in &["bot1", "bot2", "bot3"] {
+            learner.add_example(nickname, Action::Timeout(600), &[format!("{} says: \"free (viewers)\" \\o/ here", nickname)]);
+            learner.add_example(nickname, Action::Ban, &["Wanna become famous?".to_owned()]);
+            learner.add_example(nickname, Action::Ban, &["best viewers on bigfollows".to_owned()]);
+        }
+        assert_eq!(3, learner.proposals().len());
+        assert!(learner.accept(1, "a_mod").is_ok());
+        assert!(learner.reject(3).is_ok());
+
+        let mut reloaded = self::learner();
+        reloaded.load_review_file(&learner.review_file("#streamer")).unwrap();
+        assert_eq!(learner.accepted_rules(), reloaded.accepted_rules());
+        assert_eq!(2, reloaded.proposals().len());
+        assert_eq!(learner.proposals()[1].text, reloaded.proposals()[1].text);
+        assert_eq!(ProposalStatus::Pending, reloaded.proposals()[1].status);
+        assert_eq!(vec!["bot1", "bot2", "bot3"], reloaded.proposals()[1].nicknames);
+
+        // The numbers of the rejected proposals are not given again
+        for nickname in &["bot4", "bot5", "bot6"] {
+            reloaded.add_example(nickname, Action::Ban, &["another spam message".to_owned()]);
+        }
+        assert_eq!(4, reloaded.proposals()[2].id);
+    }
+}
@@ -0,0 +1,194 @@
+use time::{Duration, Tm};
+use yaml_rust::yaml::Yaml;
+
+use tags::{RoomMode, RoomStateTags};
+
+/// Room modes to turn on while the chat is attacked
+#[derive(Debug, Clone)]
+pub struct LockdownConfig {
+    /// Followers-only mode, with the minimum follow age in minutes
+    pub followers: Option<i64>,
+    /// Slow mode, with the number of seconds between two messages of a user
+    pub slow: Option<u32>,
+    pub emote_only: bool,
+    pub subs_only: bool,
+    /// Number of seconds after which the modes are lifted, even if the hammer mode is still on
+    pub duration: i64,
+}
+
+impl LockdownConfig {
+    pub fn from_yaml(token: &Yaml) -> Result<LockdownConfig, String> {
+        let followers = match token["followers"] {
+            Yaml::Integer(value) if value >= 0 => Some(value),
+            Yaml::BadValue => None,
+            _ => return Err(format!("'followers' should be a number of minutes")),
+        };
+
+        let slow = match token["slow"] {
+            Yaml::Integer(value) if value > 0 && value <= u32::max_value() as i64 => Some(value as u32),
+            Yaml::BadValue => None,
+            _ => return Err(format!("'slow' should be a positive number of seconds")),
+        };
+
+        let emote_only = match token["emote_only"] {
+            Yaml::Boolean(value) => value,
+            Yaml::BadValue => false,
+            _ => return Err(format!("'emote_only' should be true or false")),
+        };
+
+        let subs_only = match token["subs_only"] {
+            Yaml::Boolean(value) => value,
+            Yaml::BadValue => false,
+            _ => return Err(format!("'subs_only' should be true or false")),
+        };
+
+        let duration = match token["duration"] {
+            Yaml::Integer(value) if value > 0 => value,
+            Yaml::BadValue => 600,
+            _ => return Err(format!("'duration' should be a positive number of seconds")),
+        };
+
+        if followers.is_none() && slow.is_none() && !emote_only && !subs_only {
+            return Err(format!("no mode is enabled (followers, slow, emote_only or subs_only)"));
+        }
+
+        Ok(LockdownConfig {
+            followers: followers,
+            slow: slow,
+            emote_only: emote_only,
+            subs_only: subs_only,
+            duration: duration,
+        })
+    }
+}
+
+/// A mode changed by the lockdown, with the command that restores it
+struct ChangedMode {
+    mode: RoomMode,
+    restore_command: String,
+    confirmed: bool,
+}
+
+/// Turns on the lockdown modes, and remembers how the room was to restore it afterwards
+pub struct Lockdown {
+    config: LockdownConfig,
+    started: Option<Tm>,
+    changes: Vec<ChangedMode>,
+}
+
+impl Lockdown {
+    pub fn new(config: LockdownConfig) -> Lockdown {
+        Lockdown {
+            config: config,
+            started: None,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Returns the commands locking the room down. Modes that are already as strict are left alone.
+    /// Nothing is returned if the lockdown is already active.
+    pub fn start(&mut self, now: Tm, room: &RoomStateTags) -> Vec<String> {
+        if self.is_active() {
+            return Vec::new();
+        }
+        self.started = Some(now);
+        self.changes.clear();
+
+        let mut commands = Vec::new();
+        if let Some(minutes) = self.config.followers {
+            match room.followers_only {
+                Some(current) if current >= minutes => {},
+                current => {
+                    commands.push(format!("/followers {}m", minutes));
+                    self.changes.push(ChangedMode {
+                        mode: RoomMode::FollowersOnly,
+                        restore_command: match current {
+                            Some(current) if current >= 0 => format!("/followers {}m", current),
+                            _ => format!("/followersoff"),
+                        },
+                        confirmed: false,
+                    });
+                }
+            }
+        }
+        if let Some(seconds) = self.config.slow {
+            match room.slow {
+                Some(current) if current >= seconds => {},
+                current => {
+                    commands.push(format!("/slow {}", seconds));
+                    self.changes.push(ChangedMode {
+                        mode: RoomMode::Slow,
+                        restore_command: match current {
+                            Some(current) if current > 0 => format!("/slow {}", current),
+                            _ => format!("/slowoff"),
+                        },
+                        confirmed: false,
+                    });
+                }
+            }
+        }
+        if self.config.emote_only && !room.is_on(RoomMode::EmoteOnly) {
+            commands.push(format!("/emoteonly"));
+            self.changes.push(ChangedMode { mode: RoomMode::EmoteOnly, restore_command: format!("/emoteonlyoff"), confirmed: false });
+        }
+        if self.config.subs_only && !room.is_on(RoomMode::SubsOnly) {
+            commands.push(format!("/subscribers"));
+            self.changes.push(ChangedMode { mode: RoomMode::SubsOnly, restore_command: format!("/subscribersoff"), confirmed: false });
+        }
+        commands
+    }
+
+    /// Twitch confirmed that a mode is on. Returns false if the lockdown was not waiting for it.
+    pub fn confirm(&mut self, mode: RoomMode) -> bool {
+        match self.changes.iter_mut().find(|change| change.mode == mode && !change.confirmed) {
+            Some(change) => {
+                change.confirmed = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Modes that were requested, but not confirmed by Twitch yet
+    pub fn unconfirmed(&self) -> Vec<RoomMode> {
+        self.changes.iter().filter(|change| !change.confirmed).map(|change| change.mode).collect()
+    }
+
+    /// Whether the lockdown lasted for its whole duration
+    pub fn is_expired(&self, now: Tm) -> bool {
+        self.started.map_or(false, |date| now - date >= Duration::seconds(self.config.duration))
+    }
+
+    /// Returns the commands that put the room back the way it was before the lockdown
+    pub fn end(&mut self) -> Vec<String> {
+        self.started = None;
+        self.changes.drain(..).map(|change| change.restore_command).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::{Duration, now_utc};
+
+    #[test]
+    fn lockdown_restores_the_room() {
+        let mut lockdown = Lockdown::new(LockdownConfig { followers: Some(10), slow: Some(30), emote_only: true, subs_only: false, duration: 60 });
+        let room = RoomStateTags { followers_only: Some(-1), slow: Some(5), emote_only: Some(true), .. Default::default() };
+        let start = now_utc();
+        assert_eq!(vec!["/followers 10m", "/slow 30"], lockdown.start(start, &room));
+        assert!(lockdown.start(start, &room).is_empty());
+        assert!(lockdown.confirm(RoomMode::Slow));
+        assert!(!lockdown.confirm(RoomMode::EmoteOnly));
+        assert_eq!(vec![RoomMode::FollowersOnly], lockdown.unconfirmed());
+        assert!(!lockdown.is_expired(start + Duration::seconds(30)));
+        assert!(lockdown.is_expired(start + Duration::seconds(60)));
+        assert_eq!(vec!["/followersoff", "/slow 5"], lockdown.end());
+        assert!(!lockdown.is_active());
+    }
+}
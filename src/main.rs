@@ -2,27 +2,72 @@
 extern crate log;
 extern crate log4rs;
 extern crate irc;
+extern crate openssl;
+extern crate regex;
+extern crate unicode_normalization;
 extern crate time;
 extern crate yaml_rust;
 
+mod badges;
 mod checker;
 mod config;
+mod emotes;
+mod fake_tmi;
+mod ladder;
+mod learning;
+mod lockdown;
+mod normalize;
+mod pending;
 mod chat;
+mod raid;
+mod ratelimit;
+mod replay;
+mod shadow;
+mod tags;
+mod tls;
+mod wave;
 
+use std::collections::HashMap;
 use std::default::Default;
+use std::env;
 use std::io::{Result, Error, ErrorKind};
 use std::path::Path;
+use std::process::exit;
 
+use checker::Checker;
 use config::HammerConfig;
 use chat::Chat;
 
 fn main() {
     init_logger().expect("An error occured while initializing the logging system. If you don't need logging, you can just remove the 'logging.yml' file.");
 
-    let app_config = load_config().expect("An error occured while loading the application's configuration.");
+    let app_config = match load_config() {
+        Ok(app_config) => app_config,
+        Err(err) => exit_with_error(&format!("An error occured while loading the application's configuration.\n{}", err)),
+    };
 
-    let mut chat = Chat::new(&app_config);
-    chat.run();
+    let checker = match load_checker(&app_config) {
+        Ok(checker) => checker,
+        Err(err) => exit_with_error(&format!("An error occured while loading the ban rules.\n{}", err)),
+    };
+    let channel_checkers = match load_channel_checkers(&app_config) {
+        Ok(checkers) => checkers,
+        Err(err) => exit_with_error(&err),
+    };
+
+    // "purple_hammer replay <file>" runs a capture through the bot instead of connecting
+    // "purple_hammer fake-tmi <script>" runs the bot against a local fake Twitch server
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "replay" {
+        replay::run(&app_config, checker, channel_checkers, &args[2]).expect("An error occured while replaying the file.");
+    }
+    else if args.len() == 3 && args[1] == "fake-tmi" {
+        fake_tmi::run(&app_config, checker, channel_checkers, &args[2]).expect("An error occured while running the fake server.");
+    }
+    else {
+        let mut chat = Chat::new(&app_config, checker, channel_checkers);
+        chat.run();
+    }
 }
 
 fn init_logger() -> std::result::Result<(), log4rs::Error> {
@@ -36,6 +81,31 @@ fn init_logger() -> std::result::Result<(), log4rs::Error> {
     }
 }
 
+/// Stops the bot because of an error the user has to fix first
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(1);
+}
+
+fn load_checker(app_config: &HammerConfig) -> std::result::Result<Checker, checker::RuleLoadError> {
+    // validate() made sure that the rules file is set
+    let rules_file = app_config.rules.as_ref().unwrap();
+    Checker::from_file(rules_file)
+}
+
+/// Loads the rules of the channels that have their own rules file, by IRC channel name
+fn load_channel_checkers(app_config: &HammerConfig) -> std::result::Result<HashMap<String, Checker>, String> {
+    let mut result = HashMap::new();
+    for channel in app_config.channels.as_ref().unwrap() {
+        if let Some(ref rules_file) = channel.rules {
+            let checker = try!(Checker::from_file(rules_file).map_err(|err|
+                format!("An error occured while loading the ban rules of {}.\n{}", channel.name, err)));
+            result.insert(channel.irc_name(), checker);
+        }
+    }
+    Ok(result)
+}
+
 fn load_config() -> Result<HammerConfig> {
     let mut result = HammerConfig::new();
     try!(result.fill_from_file("config.yml"));
@@ -46,6 +116,10 @@ fn load_config() -> Result<HammerConfig> {
         try!(result.fill_from_file(dev_config_name));
     }
 
+    if !result.errors.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("The configuration is invalid:\n{}", result.errors.join("\n"))));
+    }
+
     if !result.validate() {
         return Err(Error::new(ErrorKind::InvalidData, "The configuration is invalid! I'm out."));
     }
@@ -0,0 +1,158 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+use yaml_rust::yaml::Yaml;
+
+/// Steps applied to a message (and to the rule patterns) before they are compared.
+/// They run in the order of the fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Normalization {
+    /// Unicode compatibility normalization (turns full-width and styled letters into plain ones)
+    pub nfkc: bool,
+    /// Removes zero-width characters, joiners and other invisible formatting characters
+    pub strip_invisible: bool,
+    /// Removes accents and other combining marks
+    pub strip_combining: bool,
+    /// Replaces letters from other scripts that look like latin letters by the latin letter
+    pub fold_confusables: bool,
+    /// Trims the text and replaces every run of whitespace by a single space
+    pub collapse_whitespace: bool,
+}
+
+impl Normalization {
+    pub fn all() -> Normalization {
+        Normalization {
+            nfkc: true,
+            strip_invisible: true,
+            strip_combining: true,
+            fold_confusables: true,
+            collapse_whitespace: true,
+        }
+    }
+
+    pub fn none() -> Normalization {
+        Normalization {
+            nfkc: false,
+            strip_invisible: false,
+            strip_combining: false,
+            fold_confusables: false,
+            collapse_whitespace: false,
+        }
+    }
+
+    /// Reads the 'normalize' key of a rule. It can either be a boolean turning all the steps
+    /// on or off, or a map enabling each step separately (missing steps are enabled).
+    pub fn from_yaml(token: &Yaml) -> Result<Normalization, String> {
+        match token {
+            &Yaml::Boolean(true) => Ok(Normalization::all()),
+            &Yaml::Boolean(false) => Ok(Normalization::none()),
+            &Yaml::Hash(ref h) => {
+                let mut result = Normalization::all();
+                for (k, v) in h {
+                    let enabled = match v {
+                        &Yaml::Boolean(value) => value,
+                        _ => return Err(format!("the normalization steps should be set to true or false ({:?})", v)),
+                    };
+                    match k.as_str() {
+                        Some("nfkc") => result.nfkc = enabled,
+                        Some("strip_invisible") => result.strip_invisible = enabled,
+                        Some("strip_combining") => result.strip_combining = enabled,
+                        Some("confusables") => result.fold_confusables = enabled,
+                        Some("whitespace") => result.collapse_whitespace = enabled,
+                        _ => return Err(format!("unknown normalization step {:?}", k)),
+                    }
+                }
+                Ok(result)
+            },
+            _ => Err(format!("the 'normalize' key should be a boolean or a map of steps ({:?})", token)),
+        }
+    }
+
+    pub fn apply(&self, input: &str) -> String {
+        let mut result: String = if self.nfkc {
+            input.nfkc().collect()
+        }
+        else {
+            input.to_owned()
+        };
+
+        if self.strip_invisible {
+            result = result.chars().filter(|&c| !is_invisible(c)).collect();
+        }
+
+        if self.strip_combining {
+            result = result.nfd().filter(|&c| !is_combining_mark(c)).nfc().collect();
+        }
+
+        if self.fold_confusables {
+            result = result.chars().map(fold_confusable).collect();
+        }
+
+        if self.collapse_whitespace {
+            result = result.split_whitespace().collect::<Vec<&str>>().join(" ");
+        }
+
+        result
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\u{00AD}' | // soft hyphen
+        '\u{034F}' | // combining grapheme joiner
+        '\u{180E}' | // mongolian vowel separator
+        '\u{200B}' ..= '\u{200F}' | // zero-width spaces, joiners and direction marks
+        '\u{202A}' ..= '\u{202E}' | // direction embeddings and overrides
+        '\u{2060}' ..= '\u{2064}' | // word joiner and invisible operators
+        '\u{FEFF}' | // zero-width no-break space
+        '\u{E0000}' ..= '\u{E007F}' => true, // tag characters
+        _ => false,
+    }
+}
+
+/// Maps the most common latin lookalikes to their latin counterpart.
+/// This is a small subset of the Unicode confusables list, focused on what spam bots use.
+fn fold_confusable(c: char) -> char {
+    match c {
+        // Cyrillic lowercase
+        'а' => 'a', 'в' => 'b', 'е' => 'e', 'ё' => 'e', 'к' => 'k', 'м' => 'm', 'н' => 'h',
+        'о' => 'o', 'р' => 'p', 'с' => 'c', 'т' => 't', 'у' => 'y', 'х' => 'x', 'ѕ' => 's',
+        'і' => 'i', 'ї' => 'i', 'ј' => 'j', 'ԁ' => 'd', 'ԛ' => 'q', 'ԝ' => 'w', 'һ' => 'h',
+        // Cyrillic uppercase
+        'А' => 'A', 'В' => 'B', 'Е' => 'E', 'Ё' => 'E', 'К' => 'K', 'М' => 'M', 'Н' => 'H',
+        'О' => 'O', 'Р' => 'P', 'С' => 'C', 'Т' => 'T', 'У' => 'Y', 'Х' => 'X', 'Ѕ' => 'S',
+        'І' => 'I', 'Ї' => 'I', 'Ј' => 'J', 'Ԛ' => 'Q', 'Ԝ' => 'W',
+        // Greek
+        'α' => 'a', 'ο' => 'o', 'ν' => 'v', 'ρ' => 'p', 'ι' => 'i', 'κ' => 'k', 'υ' => 'u',
+        'Α' => 'A', 'Β' => 'B', 'Ε' => 'E', 'Ζ' => 'Z', 'Η' => 'H', 'Ι' => 'I', 'Κ' => 'K',
+        'Μ' => 'M', 'Ν' => 'N', 'Ο' => 'O', 'Ρ' => 'P', 'Τ' => 'T', 'Υ' => 'Y', 'Χ' => 'X',
+        // Armenian
+        'ո' => 'n', 'ս' => 'u',
+        // Latin variants that survive NFKC
+        'ı' => 'i', 'ȷ' => 'j', 'ℓ' => 'l', 'ſ' => 's', 'ɡ' => 'g',
+        _ => c,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_lookalikes() {
+        // Cyrillic 'а' and 'е', full-width 'ｂ', a zero-width space and extra spaces
+        let input = "  frее  fоllоwеrs\u{200B} аt ｂit.ly  ";
+        assert_eq!("free followers at bit.ly", Normalization::all().apply(input));
+    }
+
+    #[test]
+    fn normalize_disabled_steps() {
+        let mut steps = Normalization::all();
+        steps.strip_combining = false;
+        steps.fold_confusables = false;
+        assert_eq!("café", steps.apply("café"));
+        assert_eq!("cafe", Normalization::all().apply("café"));
+        assert_eq!("саfе", steps.apply("саfе"));
+    }
+}
@@ -0,0 +1,178 @@
+use time::{Duration, Tm};
+
+use tags::MessageId;
+
+/// Seconds to wait for Twitch to confirm a moderation command, once it left the outgoing queue, before sending it again
+const CONFIRMATION_TIMEOUT : i64 = 10;
+/// Number of times a command is sent before giving up on it
+const MAX_ATTEMPTS : u32 = 3;
+
+/// What a moderation command does and to whom, to match it with the NOTICE confirming it
+#[derive(Debug, Clone, PartialEq)]
+pub enum PendingKind {
+    Ban(String),
+    Timeout(String),
+    Delete(MessageId),
+}
+
+/// Which commands a failure NOTICE can be about. Twitch does not say which user, but it answers the commands in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectedKind {
+    Ban,
+    Timeout,
+    Any,
+}
+
+/// A moderation command sent to Twitch, which did not answer yet
+#[derive(Debug, Clone)]
+pub struct PendingCommand {
+    pub kind: PendingKind,
+    pub command: String,
+    /// Sequence number of the last copy of the command in the outgoing queue
+    pub sequence: u64,
+    /// When we noticed that the last copy left the outgoing queue
+    pub sent_at: Option<Tm>,
+    pub attempts: u32,
+}
+
+/// What to do with the commands Twitch did not confirm in time
+pub enum Expired {
+    /// Send the command again, then track it with `retry`
+    Retry(PendingCommand),
+    /// The command was sent too many times, report it
+    GiveUp(PendingCommand),
+}
+
+/// The moderation commands of a channel waiting for a confirmation, oldest first
+pub struct PendingCommands {
+    commands: Vec<PendingCommand>,
+}
+
+impl PendingCommands {
+    pub fn new() -> PendingCommands {
+        PendingCommands {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Tracks a command queued as the message `sequence` of the outgoing queue
+    pub fn push(&mut self, kind: PendingKind, command: &str, sequence: u64) {
+        self.commands.push(PendingCommand {
+            kind: kind,
+            command: command.to_owned(),
+            sequence: sequence,
+            sent_at: None,
+            attempts: 1,
+        });
+    }
+
+    /// Tracks again a command given by `expire`, queued again as the message `sequence`
+    pub fn retry(&mut self, mut command: PendingCommand, sequence: u64) {
+        command.sequence = sequence;
+        command.sent_at = None;
+        command.attempts += 1;
+        self.commands.push(command);
+    }
+
+    pub fn contains(&self, kind: &PendingKind) -> bool {
+        self.commands.iter().any(|command| &command.kind == kind)
+    }
+
+    /// Removes the oldest command of this kind, now that Twitch answered it
+    pub fn resolve(&mut self, kind: &PendingKind) -> Option<PendingCommand> {
+        self.commands.iter()
+            .position(|command| &command.kind == kind)
+            .map(|pos| self.commands.remove(pos))
+    }
+
+    /// Removes the oldest command of this kind, now that Twitch refused it
+    pub fn resolve_rejected(&mut self, rejected: RejectedKind) -> Option<PendingCommand> {
+        self.commands.iter()
+            .position(|command| match (rejected, &command.kind) {
+                (RejectedKind::Any, _) | (RejectedKind::Ban, &PendingKind::Ban(_)) | (RejectedKind::Timeout, &PendingKind::Timeout(_)) => true,
+                _ => false,
+            })
+            .map(|pos| self.commands.remove(pos))
+    }
+
+    /// Removes the oldest deletion. Twitch does not say which message a deletion NOTICE is about.
+    pub fn resolve_delete(&mut self) -> Option<PendingCommand> {
+        self.commands.iter()
+            .position(|command| match command.kind { PendingKind::Delete(_) => true, _ => false })
+            .map(|pos| self.commands.remove(pos))
+    }
+
+    /// Removes the commands that waited too long since they were sent. `moderation_sent` is the sequence number
+    /// of the last moderation command that left the outgoing queue; the commands still queued never expire.
+    pub fn expire(&mut self, now: Tm, moderation_sent: u64) -> Vec<Expired> {
+        let mut result = Vec::new();
+        let mut kept = Vec::new();
+        for mut command in self.commands.drain(..) {
+            if command.sent_at.is_none() && command.sequence <= moderation_sent {
+                command.sent_at = Some(now);
+            }
+            match command.sent_at {
+                Some(sent_at) if now - sent_at >= Duration::seconds(CONFIRMATION_TIMEOUT) => {
+                    if command.attempts >= MAX_ATTEMPTS {
+                        result.push(Expired::GiveUp(command));
+                    }
+                    else {
+                        result.push(Expired::Retry(command));
+                    }
+                },
+                _ => kept.push(command),
+            }
+        }
+        self.commands = kept;
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::{Duration, now_utc};
+
+    #[test]
+    fn resolve_and_expire() {
+        let mut pending = PendingCommands::new();
+        let start = now_utc();
+        pending.push(PendingKind::Ban("spambot".to_owned()), "/ban spambot", 1);
+        pending.push(PendingKind::Timeout("spambot2".to_owned()), "/timeout spambot2 600", 2);
+        assert!(pending.resolve(&PendingKind::Timeout("spambot".to_owned())).is_none());
+        assert!(pending.resolve_delete().is_none());
+        assert_eq!("/ban spambot", pending.resolve(&PendingKind::Ban("spambot".to_owned())).unwrap().command);
+        assert!(pending.resolve_rejected(RejectedKind::Ban).is_none());
+        assert_eq!(1, pending.len());
+
+        // Still in the outgoing queue, however long it takes
+        assert!(pending.expire(start + Duration::seconds(60), 1).is_empty());
+        assert!(pending.expire(start + Duration::seconds(65), 2).is_empty());
+        assert!(pending.expire(start + Duration::seconds(70), 2).is_empty());
+        match pending.expire(start + Duration::seconds(75), 2).pop() {
+            Some(Expired::Retry(command)) => pending.retry(command, 3),
+            _ => panic!("the timeout should be sent again"),
+        }
+        assert_eq!(1, pending.len());
+        assert!(pending.expire(start + Duration::seconds(100), 2).is_empty());
+        assert!(pending.expire(start + Duration::seconds(100), 3).is_empty());
+        match pending.expire(start + Duration::seconds(110), 3).pop() {
+            Some(Expired::Retry(command)) => {
+                assert_eq!(2, command.attempts);
+                pending.retry(command, 4);
+            },
+            _ => panic!("the timeout should be sent again"),
+        }
+        assert!(pending.expire(start + Duration::seconds(110), 4).is_empty());
+        match pending.expire(start + Duration::seconds(120), 4).pop() {
+            Some(Expired::GiveUp(command)) => assert_eq!(PendingKind::Timeout("spambot2".to_owned()), command.kind),
+            _ => panic!("the timeout should be given up"),
+        }
+        assert_eq!(0, pending.len());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use time::{Duration, Tm};
+use yaml_rust::yaml::Yaml;
+
+#[derive(Debug, Clone)]
+pub struct RaidConfig {
+    /// Number of JOINs in the window that starts a raid
+    pub joins: usize,
+    /// Size of the sliding window, in seconds
+    pub window: i64,
+    /// Number of seconds without a burst of JOINs after which the raid is over
+    pub quiet_period: i64,
+}
+
+impl RaidConfig {
+    pub fn from_yaml(token: &Yaml) -> Result<RaidConfig, String> {
+        let joins = match token["joins"] {
+            Yaml::Integer(value) if value > 0 => value as usize,
+            _ => return Err(format!("'joins' should be a positive number")),
+        };
+
+        let window = match token["window"] {
+            Yaml::Integer(value) if value > 0 => value,
+            _ => return Err(format!("'window' should be a positive number of seconds")),
+        };
+
+        let quiet_period = match token["quiet_period"] {
+            Yaml::Integer(value) if value > 0 => value,
+            Yaml::BadValue => 300,
+            _ => return Err(format!("'quiet_period' should be a positive number of seconds")),
+        };
+
+        Ok(RaidConfig {
+            joins: joins,
+            window: window,
+            quiet_period: quiet_period,
+        })
+    }
+}
+
+/// What to do when Twitch announces that another channel is raiding
+#[derive(Debug, Clone)]
+pub struct IncomingRaidConfig {
+    /// Turns the hammer mode on when the raiding channel is not a friend
+    pub auto_hammer: bool,
+    /// Channels whose raids are always welcome (lowercase)
+    pub friends: Vec<String>,
+}
+
+impl IncomingRaidConfig {
+    pub fn from_yaml(token: &Yaml) -> Result<IncomingRaidConfig, String> {
+        let auto_hammer = match token["auto_hammer"] {
+            Yaml::Boolean(value) => value,
+            Yaml::BadValue => true,
+            _ => return Err(format!("'auto_hammer' should be true or false")),
+        };
+
+        let friends = match token["friends"] {
+            Yaml::Array(ref names) => {
+                let mut list = Vec::new();
+                for name in names {
+                    match name {
+                        &Yaml::String(ref name) => list.push(name.trim_left_matches('#').to_lowercase()),
+                        _ => return Err(format!("'friends' should be a list of channel names")),
+                    }
+                }
+                list
+            },
+            Yaml::BadValue => Vec::new(),
+            _ => return Err(format!("'friends' should be a list of channel names")),
+        };
+
+        Ok(IncomingRaidConfig {
+            auto_hammer: auto_hammer,
+            friends: friends,
+        })
+    }
+
+    pub fn is_friend(&self, channel: &str) -> bool {
+        self.friends.iter().any(|friend| friend.as_str() == channel.to_lowercase())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RaidEvent {
+    /// A burst of JOINs was detected (JOINs in the window)
+    Started(usize),
+    /// There was no burst for the whole quiet period (JOINs in the window)
+    Ended(usize),
+}
+
+/// Counts the users joining the channel, to detect raids before the bots start talking
+pub struct RaidDetector {
+    config: RaidConfig,
+    joins: VecDeque<Tm>,
+    /// Last time the threshold was reached, while a raid is going on
+    last_burst: Option<Tm>,
+}
+
+impl RaidDetector {
+    pub fn new(config: RaidConfig) -> RaidDetector {
+        RaidDetector {
+            config: config,
+            joins: VecDeque::new(),
+            last_burst: None,
+        }
+    }
+
+    pub fn config(&self) -> &RaidConfig {
+        &self.config
+    }
+
+    pub fn on_join(&mut self, now: Tm) -> Option<RaidEvent> {
+        self.joins.push_back(now);
+        self.forget_old_joins(now);
+
+        if self.joins.len() < self.config.joins {
+            return None;
+        }
+
+        let started = self.last_burst.is_none();
+        self.last_burst = Some(now);
+        if started {
+            Some(RaidEvent::Started(self.joins.len()))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Twitch announced a raid: it lasts until the quiet period is over, as if the JOINs had been seen.
+    /// Returns false if a raid was already going on.
+    pub fn on_raid_notice(&mut self, now: Tm) -> bool {
+        let started = self.last_burst.is_none();
+        self.last_burst = Some(now);
+        started
+    }
+
+    /// Checks if the raid is over. This should be called regularly.
+    pub fn tick(&mut self, now: Tm) -> Option<RaidEvent> {
+        self.forget_old_joins(now);
+        match self.last_burst {
+            Some(date) if now - date >= Duration::seconds(self.config.quiet_period) => {
+                self.last_burst = None;
+                Some(RaidEvent::Ended(self.joins.len()))
+            },
+            _ => None,
+        }
+    }
+
+    fn forget_old_joins(&mut self, now: Tm) {
+        let window_start = now - Duration::seconds(self.config.window);
+        while self.joins.front().map_or(false, |&date| date < window_start) {
+            self.joins.pop_front();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::{Duration, now_utc};
+
+    #[test]
+    fn raid_start_and_end() {
+        let mut detector = RaidDetector::new(RaidConfig { joins: 3, window: 10, quiet_period: 60 });
+        let start = now_utc();
+        assert_eq!(None, detector.on_join(start));
+        assert_eq!(None, detector.on_join(start + Duration::seconds(1)));
+        assert_eq!(Some(RaidEvent::Started(3)), detector.on_join(start + Duration::seconds(2)));
+        assert_eq!(None, detector.on_join(start + Duration::seconds(3)));
+        assert_eq!(None, detector.tick(start + Duration::seconds(30)));
+        assert_eq!(Some(RaidEvent::Ended(0)), detector.tick(start + Duration::seconds(63)));
+        assert_eq!(None, detector.tick(start + Duration::seconds(90)));
+    }
+
+    #[test]
+    fn raid_notice() {
+        let mut detector = RaidDetector::new(RaidConfig { joins: 3, window: 10, quiet_period: 60 });
+        let start = now_utc();
+        assert!(detector.on_raid_notice(start));
+        assert_eq!(None, detector.on_join(start + Duration::seconds(1)));
+        assert_eq!(Some(RaidEvent::Ended(0)), detector.tick(start + Duration::seconds(61)));
+    }
+}
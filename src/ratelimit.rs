@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use irc::client::prelude::*;
+
+/// Twitch allows 20 messages per 30 seconds for normal users...
+const USER_LIMIT: u32 = 20;
+/// ... and 100 messages per 30 seconds for moderators
+const MODERATOR_LIMIT: u32 = 100;
+const LIMIT_PERIOD_SECS: u64 = 30;
+
+/// Chat messages waiting longer than that are not worth sending anymore
+const MAX_CHAT_QUEUE: usize = 20;
+/// Moderation commands are only dropped when things go really wrong
+const MAX_MODERATION_QUEUE: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Bans, timeouts, deletions and mode changes; sent first
+    Moderation,
+    /// Announcements and answers to commands
+    Chat,
+}
+
+#[derive(Debug)]
+pub struct Outgoing {
+    pub target: String,
+    pub text: String,
+    /// Position of the message among all the messages pushed
+    pub sequence: u64,
+}
+
+/// Sliding window over the last messages sent: no period of that length ever holds more than `limit` messages,
+/// which is how Twitch counts them
+struct SendWindow {
+    limit: u32,
+    period: Duration,
+    /// When the messages of the last period were sent, oldest first
+    sent: VecDeque<Instant>,
+}
+
+impl SendWindow {
+    fn new(limit: u32, period: Duration) -> SendWindow {
+        SendWindow {
+            limit: limit,
+            period: period,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Records a message sent at `now` if the limit allows it, or returns how long to wait before trying again
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        while self.sent.front().map_or(false, |&date| now.duration_since(date) >= self.period) {
+            self.sent.pop_front();
+        }
+
+        let count = self.sent.len() as u32;
+        if count < self.limit {
+            self.sent.push_back(now);
+            Ok(())
+        }
+        else {
+            // After lowering the limit, more than the oldest message have to leave the window
+            let blocking = self.sent[(count - self.limit) as usize];
+            Err(self.period - now.duration_since(blocking))
+        }
+    }
+
+    /// The messages already sent keep counting against the new limit
+    fn set_limit(&mut self, limit: u32) {
+        self.limit = limit;
+    }
+}
+
+struct QueueState {
+    moderation: VecDeque<Outgoing>,
+    chat: VecDeque<Outgoing>,
+    window: SendWindow,
+    is_moderator: bool,
+    dropped: u64,
+    /// Sequence number of the last message pushed
+    pushed: u64,
+    /// Sequence number of the last moderation command taken out of the queue
+    moderation_sent: u64,
+}
+
+/// Outgoing messages, sent by a background thread at the pace Twitch allows
+pub struct Outbox {
+    state: Mutex<QueueState>,
+    wakeup: Condvar,
+}
+
+/// Numbers about the outgoing queue, for reporting
+pub struct OutboxStats {
+    pub moderation_queued: usize,
+    pub chat_queued: usize,
+    pub dropped: u64,
+    pub limit: u32,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox {
+            state: Mutex::new(QueueState {
+                moderation: VecDeque::new(),
+                chat: VecDeque::new(),
+                // Start with the lowest limit until we know we are a moderator
+                window: SendWindow::new(USER_LIMIT, Duration::from_secs(LIMIT_PERIOD_SECS)),
+                is_moderator: false,
+                dropped: 0,
+                pushed: 0,
+                moderation_sent: 0,
+            }),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Queues a message, and returns its sequence number to compare with `moderation_sent`
+    pub fn push(&self, priority: Priority, target: &str, text: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.pushed += 1;
+        let sequence = state.pushed;
+        let message = Outgoing { target: target.to_owned(), text: text.to_owned(), sequence: sequence };
+        let dropped = {
+            let (queue, max_length) = match priority {
+                Priority::Moderation => (&mut state.moderation, MAX_MODERATION_QUEUE),
+                Priority::Chat => (&mut state.chat, MAX_CHAT_QUEUE),
+            };
+
+            let dropped = if queue.len() >= max_length {
+                // Drop the oldest message, the newest ones are the most relevant
+                queue.pop_front()
+            }
+            else {
+                None
+            };
+            queue.push_back(message);
+            dropped
+        };
+
+        if let Some(dropped) = dropped {
+            state.dropped += 1;
+            warn!("The outgoing {:?} queue is full, dropped '{}' ({} messages dropped so far)", priority, dropped.text, state.dropped);
+        }
+        else if state.moderation.len() + state.chat.len() > 1 {
+            debug!("Outgoing queue depth: {} moderation commands, {} chat messages", state.moderation.len(), state.chat.len());
+        }
+
+        self.wakeup.notify_one();
+        sequence
+    }
+
+    /// Sequence number of the last moderation command sent (or dropped). The moderation commands leave the
+    /// queue in order, so every command with a lower number is gone too.
+    pub fn moderation_sent(&self) -> u64 {
+        self.state.lock().unwrap().moderation_sent
+    }
+
+    pub fn set_moderator(&self, is_moderator: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.is_moderator != is_moderator {
+            let limit = if is_moderator { MODERATOR_LIMIT } else { USER_LIMIT };
+            info!("Outgoing rate limit is now {} messages per {}s", limit, LIMIT_PERIOD_SECS);
+            state.is_moderator = is_moderator;
+            state.window.set_limit(limit);
+        }
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let state = self.state.lock().unwrap();
+        OutboxStats {
+            moderation_queued: state.moderation.len(),
+            chat_queued: state.chat.len(),
+            dropped: state.dropped,
+            limit: if state.is_moderator { MODERATOR_LIMIT } else { USER_LIMIT },
+        }
+    }
+
+    /// Removes every queued message at once, ignoring the rate limit
+    pub fn drain(&self) -> Vec<Outgoing> {
+        let mut state = self.state.lock().unwrap();
+        let mut result: Vec<Outgoing> = state.moderation.drain(..).collect();
+        if let Some(last) = result.last() {
+            state.moderation_sent = last.sequence;
+        }
+        result.extend(state.chat.drain(..));
+        result
+    }
+
+    /// Blocks until a message can be sent, and returns it
+    fn next(&self) -> Outgoing {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.moderation.is_empty() && state.chat.is_empty() {
+                state = self.wakeup.wait(state).unwrap();
+                continue;
+            }
+
+            match state.window.take(Instant::now()) {
+                Ok(()) => {
+                    let message = match state.moderation.pop_front() {
+                        Some(message) => {
+                            state.moderation_sent = message.sequence;
+                            message
+                        },
+                        None => state.chat.pop_front().unwrap(),
+                    };
+                    return message;
+                },
+                Err(wait) => {
+                    // Let the other threads queue messages while we wait
+                    drop(state);
+                    sleep(wait);
+                    state = self.state.lock().unwrap();
+                },
+            }
+        }
+    }
+
+    /// Starts the thread sending the queued messages to the server
+    pub fn start_sender(outbox: Arc<Outbox>, server: IrcServer) {
+        spawn(move || {
+            loop {
+                let message = outbox.next();
+                if let Err(error) = server.send_privmsg(message.target.as_str(), message.text.as_str()) {
+                    error!("Could not send a message on {}!", message.target);
+                    debug!(" - Message was '{}'", message.text);
+                    debug!(" - Error was {}", error);
+                }
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn moderation_first() {
+        let outbox = Outbox::new();
+        outbox.push(Priority::Chat, "#chan", "hello");
+        outbox.push(Priority::Moderation, "#chan", "/ban bot");
+        let ban = outbox.push(Priority::Moderation, "#chan", "/ban bot2");
+        assert_eq!("/ban bot", outbox.next().text);
+        assert!(outbox.moderation_sent() < ban);
+        assert_eq!("/ban bot2", outbox.next().text);
+        assert_eq!(ban, outbox.moderation_sent());
+        assert_eq!("hello", outbox.next().text);
+    }
+
+    #[test]
+    fn chat_queue_drops_oldest() {
+        let outbox = Outbox::new();
+        for i in 0..MAX_CHAT_QUEUE + 2 {
+            outbox.push(Priority::Chat, "#chan", &format!("message {}", i));
+        }
+        let stats = outbox.stats();
+        assert_eq!(MAX_CHAT_QUEUE, stats.chat_queued);
+        assert_eq!(2, stats.dropped);
+        assert_eq!("message 2", outbox.drain()[0].text);
+    }
+
+    #[test]
+    fn window_never_exceeds_the_limit() {
+        let period = Duration::from_secs(LIMIT_PERIOD_SECS);
+        let mut window = SendWindow::new(USER_LIMIT, period);
+        let start = Instant::now();
+        let mut now = start;
+        let mut sent = Vec::new();
+        for i in 0..3 * USER_LIMIT {
+            // Some messages are queued while the previous ones wait
+            now += Duration::from_millis(100 * (i % 3) as u64);
+            loop {
+                match window.take(now) {
+                    Ok(()) => break,
+                    Err(wait) => now += wait,
+                }
+            }
+            sent.push(now);
+        }
+
+        let limit = USER_LIMIT as usize;
+        for pos in limit..sent.len() {
+            assert!(sent[pos] - sent[pos - limit] >= period, "message {} went out too early", pos);
+        }
+        // The first batch goes out right away
+        assert!(sent[limit - 1] - start < Duration::from_secs(5));
+        assert!(sent[3 * limit - 1] - start >= 2 * period);
+    }
+
+    #[test]
+    fn lower_limit_waits_for_the_window() {
+        let period = Duration::from_secs(LIMIT_PERIOD_SECS);
+        let mut window = SendWindow::new(4, period);
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(window.take(start).is_ok());
+        }
+        window.set_limit(2);
+        assert_eq!(period, window.take(start).unwrap_err());
+        assert!(window.take(start + period).is_ok());
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Result};
+use std::fs::File;
+use std::path::Path;
+
+use irc::client::conn::MockConnection;
+use irc::client::prelude::*;
+
+use chat::Chat;
+use checker::Checker;
+use config::HammerConfig;
+
+/// Prefix of the received messages in the debug logs, so that logs can be replayed as they are
+const LOG_PREFIX : &'static str = "Message received : ";
+
+/// Extracts the IRC line from a line of a capture file. Blank lines and "//" comments give None.
+pub fn raw_line(line: &str) -> Option<&str> {
+    let raw = match line.find(LOG_PREFIX) {
+        Some(start) => &line[start + LOG_PREFIX.len()..],
+        None => line,
+    }.trim();
+    if raw.is_empty() || raw.starts_with("//") {
+        None
+    }
+    else {
+        Some(raw)
+    }
+}
+
+/// Feeds a file of raw IRC lines through the chat, without connecting to anything,
+/// and prints every command the bot would have sent.
+/// The time-based detections follow the timestamps of the lines (see `Chat::replay_line`),
+/// so a capture is judged as it happened, however fast it is replayed.
+pub fn run<P: AsRef<Path>>(conf: &HammerConfig, checker: Checker, channel_checkers: HashMap<String, Checker>, source: P) -> Result<()> {
+    let file = BufReader::new(try!(File::open(source)));
+    let server = IrcServer::from_connection(conf.to_irc_config(), MockConnection::empty());
+    let mut chat = Chat::from_server(conf, checker, channel_checkers, server);
+
+    let mut sent_length = 0;
+    let mut line_count = 0;
+    let mut action_count = 0;
+    for (pos, line) in file.lines().enumerate() {
+        let line = try!(line);
+        let raw = match raw_line(&line) {
+            Some(raw) => raw,
+            None => continue,
+        };
+
+        line_count += 1;
+        if let Err(err) = chat.replay_line(raw) {
+            println!("line {}: could not parse '{}': {}", pos + 1, raw, err);
+            continue;
+        }
+
+        let sent = chat.sent_log().unwrap_or(String::new());
+        for command in sent[sent_length..].lines() {
+            action_count += 1;
+            println!("line {}: {}", pos + 1, command);
+        }
+        sent_length = sent.len();
+    }
+
+    println!("Replayed {} lines, {} commands would have been sent.", line_count, action_count);
+    Ok(())
+}
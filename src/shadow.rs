@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use time::Tm;
+
+use checker::Action;
+
+/// A punishment that was decided but not sent, because the shadow mode is on
+#[derive(Debug)]
+pub struct ShadowDecision {
+    pub date: Tm,
+    pub nickname: String,
+    pub rule_id: String,
+    pub action: Action,
+}
+
+/// Keeps the decisions taken in shadow mode, so that mods can review them
+pub struct ShadowLog {
+    decisions: Vec<ShadowDecision>,
+}
+
+impl ShadowLog {
+    pub fn new() -> ShadowLog {
+        ShadowLog {
+            decisions: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, decision: ShadowDecision) {
+        info!("SHADOW: would have applied '{}' to '{}' for rule '{}'", decision.action, decision.nickname, decision.rule_id);
+        self.decisions.push(decision);
+    }
+
+    pub fn clear(&mut self) {
+        self.decisions.clear();
+    }
+
+    /// Short summary of the recorded decisions, that fits in a chat message
+    pub fn summary(&self) -> String {
+        // Only list the most used rules
+        const MAX_RULES: usize = 5;
+
+        if self.decisions.is_empty() {
+            return format!("Shadow mode: no decisions recorded.");
+        }
+
+        let mut per_action: HashMap<&'static str, usize> = HashMap::new();
+        let mut per_rule: HashMap<&str, usize> = HashMap::new();
+        for decision in &self.decisions {
+            let action_name = match decision.action {
+                Action::Delete => "deletes",
+                Action::Timeout(_) => "timeouts",
+                Action::Ban => "bans",
+                Action::Warn => "warnings",
+            };
+            *per_action.entry(action_name).or_insert(0) += 1;
+            *per_rule.entry(decision.rule_id.as_str()).or_insert(0) += 1;
+        }
+
+        let mut actions: Vec<(&&str, &usize)> = per_action.iter().collect();
+        actions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let mut rules: Vec<(&&str, &usize)> = per_rule.iter().collect();
+        rules.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        let last = self.decisions.last().unwrap();
+        format!("Shadow mode: {} decisions since {} UTC ({}). Top rules: {}. Last: '{}' for {}.",
+            self.decisions.len(),
+            self.decisions[0].date.strftime("%H:%M").unwrap(),
+            actions.iter().map(|&(name, count)| format!("{} {}", count, name)).collect::<Vec<String>>().join(", "),
+            rules.iter().take(MAX_RULES).map(|&(id, count)| format!("{} ({})", id, count)).collect::<Vec<String>>().join(", "),
+            last.nickname,
+            last.rule_id)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::now_utc;
+
+    #[test]
+    fn shadow_summary() {
+        let mut log = ShadowLog::new();
+        assert_eq!("Shadow mode: no decisions recorded.", log.summary());
+
+        for &(nickname, rule_id, action) in &[("bot1", "spam", Action::Ban), ("bot2", "spam", Action::Ban), ("bot3", "link", Action::Timeout(60))] {
+            log.record(ShadowDecision { date: now_utc(), nickname: nickname.to_owned(), rule_id: rule_id.to_owned(), action: action });
+        }
+        let summary = log.summary();
+        assert!(summary.starts_with("Shadow mode: 3 decisions since"));
+        assert!(summary.contains("(2 bans, 1 timeouts). Top rules: spam (2), link (1). Last: 'bot3' for link."));
+    }
+}
@@ -0,0 +1,521 @@
+use std::fmt;
+use std::str::FromStr;
+
+use irc::client::data::message::Tag;
+use time::{Timespec, Tm, at_utc};
+
+use badges::TwitchBadge;
+use emotes::Emote;
+
+pub enum TwitchUserType {
+    None,
+    Mod,
+    GlobalMod,
+    Admin,
+    Staff,
+    Other(String),
+}
+
+impl Default for TwitchUserType {
+    fn default() -> TwitchUserType {
+        TwitchUserType::None
+    }
+}
+
+impl From<String> for TwitchUserType {
+    fn from(input: String) -> TwitchUserType {
+        match input.as_str() {
+            "" => TwitchUserType::None,
+            "mod" => TwitchUserType::Mod,
+            "global_mod" => TwitchUserType::GlobalMod,
+            "admin" => TwitchUserType::Admin,
+            "staff" => TwitchUserType::Staff,
+            _ => TwitchUserType::Other(input),
+        }
+    }
+}
+
+/// ID of a chat message, which Twitch gives as a UUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId([u8; 16]);
+
+impl FromStr for MessageId {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<MessageId, String> {
+        let groups: Vec<&str> = text.split('-').collect();
+        let group_lengths: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        if group_lengths != [8, 4, 4, 4, 12] {
+            return Err(format!("'{}' is not a valid message ID", text));
+        }
+
+        let digits: String = groups.concat();
+        if !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(format!("'{}' is not a valid message ID", text));
+        }
+        let mut bytes = [0u8; 16];
+        for (pos, byte) in bytes.iter_mut().enumerate() {
+            *byte = try!(u8::from_str_radix(&digits[pos * 2..pos * 2 + 2], 16)
+                .map_err(|_| format!("'{}' is not a valid message ID", text)));
+        }
+        Ok(MessageId(bytes))
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pos, byte) in self.0.iter().enumerate() {
+            if pos == 4 || pos == 6 || pos == 8 || pos == 10 {
+                try!(write!(f, "-"));
+            }
+            try!(write!(f, "{:02x}", byte));
+        }
+        Ok(())
+    }
+}
+
+/// The message this message answers to, when it is a reply
+#[derive(Default)]
+pub struct ReplyParent {
+    pub id: Option<MessageId>,
+    pub user_id: Option<u64>,
+    pub login: Option<String>,
+    pub display_name: Option<String>,
+    pub body: Option<String>,
+}
+
+/// Tags of a chat message (PRIVMSG)
+#[derive(Default)]
+pub struct MessageTagData {
+    pub badges: Option<Vec<TwitchBadge>>,
+    /// Exact values of some badges, like the number of months for subscribers
+    pub badge_info: Option<Vec<TwitchBadge>>,
+    /// Amount of bits cheered with this message
+    pub bits: Option<u32>,
+    pub client_nonce: Option<String>,
+    pub color: Option<String>,
+    pub display_name: Option<String>,
+    pub emotes: Option<Vec<Emote>>,
+    /// First message of the user in this channel
+    pub first_message: Option<bool>,
+    pub id: Option<MessageId>,
+    pub is_mod: Option<bool>,
+    pub is_subscriber: Option<bool>,
+    pub is_turbo: Option<bool>,
+    pub is_vip: Option<bool>,
+    pub reply_parent: Option<ReplyParent>,
+    /// The user chatted before, but not for a while
+    pub returning_chatter: Option<bool>,
+    pub room_id: Option<u64>,
+    /// When the server received the message
+    pub sent_at: Option<Tm>,
+    pub user_id: Option<u64>,
+    pub user_type: Option<TwitchUserType>,
+}
+
+impl MessageTagData {
+    pub fn from_tags(tags: Vec<Tag>) -> Result<MessageTagData, String> {
+        let mut result = MessageTagData {
+            ..Default::default()
+        };
+        let mut reply_parent = ReplyParent::default();
+
+        for tag in tags {
+            let Tag(key, val_opt) = tag;
+            if let Some(val) = val_opt {
+                match key.as_str() {
+                    "badges" => result.badges = Some(TwitchBadge::parse_list(val.as_str())),
+                    "badge-info" => result.badge_info = Some(TwitchBadge::parse_list(val.as_str())),
+                    "bits" => result.bits = Some(try!(parse_number(&key, &val))),
+                    "client-nonce" => result.client_nonce = Some(unescape_value(&val)),
+                    "color" => result.color = Some(val),
+                    "display-name" => result.display_name = Some(unescape_value(&val)),
+                    "emotes" => match Emote::parse_list(val.as_str()) {
+                        Ok(emotes) => result.emotes = Some(emotes),
+                        // Not a reason to ignore the message
+                        Err(msg) => warn!("Could not parse the emotes '{}': {}", val, msg),
+                    },
+                    "first-msg" => result.first_message = Some(val == "1"),
+                    "id" => result.id = parse_message_id(&val),
+                    "mod" => result.is_mod = Some(val == "1"),
+                    "returning-chatter" => result.returning_chatter = Some(val == "1"),
+                    "subscriber" => result.is_subscriber = Some(val == "1"),
+                    "turbo" => result.is_turbo = Some(val == "1"),
+                    "vip" => result.is_vip = Some(val == "1"),
+                    "room-id" => result.room_id = Some(try!(parse_number(&key, &val))),
+                    "user-id" => result.user_id = Some(try!(parse_number(&key, &val))),
+                    "user-type" => result.user_type = Some(TwitchUserType::from(val)),
+                    "tmi-sent-ts" => result.sent_at = Some(try!(parse_timestamp(&val))),
+                    "reply-parent-msg-id" => reply_parent.id = parse_message_id(&val),
+                    "reply-parent-user-id" => reply_parent.user_id = Some(try!(parse_number(&key, &val))),
+                    "reply-parent-user-login" => reply_parent.login = Some(val),
+                    "reply-parent-display-name" => reply_parent.display_name = Some(unescape_value(&val)),
+                    "reply-parent-msg-body" => reply_parent.body = Some(unescape_value(&val)),
+                    // Only used by the chat clients
+                    "flags" | "emote-only" | "reply-thread-parent-msg-id" | "reply-thread-parent-user-login" => {},
+                    &_ => debug!("Unexpected message tag: {}={}", key, val),
+                }
+            }
+        };
+
+        if reply_parent.id.is_some() || reply_parent.login.is_some() {
+            result.reply_parent = Some(reply_parent);
+        }
+
+        Ok(result)
+    }
+}
+
+/// A mode of the chat room, which limits who can talk or what can be said
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomMode {
+    EmoteOnly,
+    FollowersOnly,
+    R9k,
+    Slow,
+    SubsOnly,
+}
+
+impl RoomMode {
+    pub fn from_name(name: &str) -> Option<RoomMode> {
+        match name {
+            "emote_only" => Some(RoomMode::EmoteOnly),
+            "followers_only" => Some(RoomMode::FollowersOnly),
+            "r9k" => Some(RoomMode::R9k),
+            "slow" => Some(RoomMode::Slow),
+            "subs_only" => Some(RoomMode::SubsOnly),
+            _ => None,
+        }
+    }
+}
+
+/// Tags of a ROOMSTATE. Twitch sends all of them when joining a channel, then only the ones that change.
+#[derive(Debug, Clone, Default)]
+pub struct RoomStateTags {
+    pub emote_only: Option<bool>,
+    /// How long users must have followed the channel to talk, in minutes; -1 when the mode is off
+    pub followers_only: Option<i64>,
+    pub language: Option<String>,
+    pub r9k: Option<bool>,
+    pub room_id: Option<u64>,
+    /// Seconds between two messages of a user; 0 when the mode is off
+    pub slow: Option<u32>,
+    pub subs_only: Option<bool>,
+}
+
+impl RoomStateTags {
+    pub fn from_tags(tags: Vec<Tag>) -> RoomStateTags {
+        let mut result = RoomStateTags::default();
+        for tag in tags {
+            let Tag(key, val_opt) = tag;
+            if let Some(val) = val_opt {
+                match key.as_str() {
+                    "emote-only" => result.emote_only = Some(val == "1"),
+                    "followers-only" => result.followers_only = parse_number(&key, &val).ok(),
+                    "broadcaster-lang" | "language" => result.language = Some(val),
+                    "r9k" => result.r9k = Some(val == "1"),
+                    "room-id" => result.room_id = parse_number(&key, &val).ok(),
+                    "slow" => result.slow = parse_number(&key, &val).ok(),
+                    "subs-only" => result.subs_only = Some(val == "1"),
+                    &_ => debug!("Unexpected room state tag: {}={}", key, val),
+                }
+            }
+        }
+        result
+    }
+
+    /// Applies an update, which only has the tags that changed
+    pub fn merge(&mut self, update: RoomStateTags) {
+        self.emote_only = update.emote_only.or(self.emote_only.take());
+        self.followers_only = update.followers_only.or(self.followers_only.take());
+        self.language = update.language.or(self.language.take());
+        self.r9k = update.r9k.or(self.r9k.take());
+        self.room_id = update.room_id.or(self.room_id.take());
+        self.slow = update.slow.or(self.slow.take());
+        self.subs_only = update.subs_only.or(self.subs_only.take());
+    }
+
+    /// Whether a mode is on. Modes we were not told about are considered off.
+    pub fn is_on(&self, mode: RoomMode) -> bool {
+        match mode {
+            RoomMode::EmoteOnly => self.emote_only.unwrap_or(false),
+            RoomMode::FollowersOnly => self.followers_only.map_or(false, |minutes| minutes >= 0),
+            RoomMode::R9k => self.r9k.unwrap_or(false),
+            RoomMode::Slow => self.slow.map_or(false, |seconds| seconds > 0),
+            RoomMode::SubsOnly => self.subs_only.unwrap_or(false),
+        }
+    }
+}
+
+impl fmt::Display for RoomStateTags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on_off = |value: Option<bool>| match value {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unknown",
+        };
+        let followers = match self.followers_only {
+            Some(minutes) if minutes >= 0 => format!("{} minutes", minutes),
+            Some(_) => format!("off"),
+            None => format!("unknown"),
+        };
+        let slow = match self.slow {
+            Some(seconds) if seconds > 0 => format!("{}s", seconds),
+            Some(_) => format!("off"),
+            None => format!("unknown"),
+        };
+        write!(f, "emote-only {}, followers-only {}, slow {}, subs-only {}, r9k {}",
+            on_off(self.emote_only), followers, slow, on_off(self.subs_only), on_off(self.r9k))
+    }
+}
+
+/// Tags of USERSTATE (for a channel) and GLOBALUSERSTATE (after the login), which describe the bot itself
+#[derive(Default)]
+pub struct UserStateTags {
+    pub badges: Vec<TwitchBadge>,
+    pub display_name: Option<String>,
+    pub is_mod: bool,
+    /// Only given by GLOBALUSERSTATE
+    pub user_id: Option<u64>,
+}
+
+impl UserStateTags {
+    pub fn from_tags(tags: Vec<Tag>) -> UserStateTags {
+        let mut result = UserStateTags::default();
+        for tag in tags {
+            let Tag(key, val_opt) = tag;
+            if let Some(val) = val_opt {
+                match key.as_str() {
+                    "badges" => result.badges = TwitchBadge::parse_list(val.as_str()),
+                    "display-name" => result.display_name = Some(unescape_value(&val)),
+                    "mod" => result.is_mod = val == "1",
+                    "user-id" => result.user_id = parse_number(&key, &val).ok(),
+                    &_ => {},
+                }
+            }
+        }
+        result
+    }
+
+    /// Broadcasters and moderators can use the moderation commands
+    pub fn can_moderate(&self) -> bool {
+        self.is_mod || self.badges.iter().any(TwitchBadge::is_moderator)
+    }
+}
+
+/// Tags of a USERNOTICE: subscriptions, gifts, raids... The "msg-id" tag tells which one it is.
+#[derive(Default)]
+pub struct UserNoticeTags {
+    pub kind: Option<String>,
+    /// The user who subscribed, gifted, or raided
+    pub login: Option<String>,
+    pub display_name: Option<String>,
+    pub user_id: Option<u64>,
+    /// The announcement shown in the chat
+    pub system_message: Option<String>,
+    /// Total number of months subscribed
+    pub cumulative_months: Option<u32>,
+    /// "Prime", "1000", "2000" or "3000"
+    pub sub_plan: Option<String>,
+    /// Login of the user who received a gifted sub
+    pub recipient_login: Option<String>,
+    pub recipient_display_name: Option<String>,
+    /// Number of subs given at once by a mystery gift
+    pub gift_count: Option<u32>,
+    /// Number of viewers coming with a raid
+    pub viewer_count: Option<u32>,
+    /// Name of the ritual, like "new_chatter"
+    pub ritual_name: Option<String>,
+    /// When the server sent the notice
+    pub sent_at: Option<Tm>,
+}
+
+impl UserNoticeTags {
+    pub fn from_tags(tags: Vec<Tag>) -> UserNoticeTags {
+        let mut result = UserNoticeTags::default();
+        for tag in tags {
+            let Tag(key, val_opt) = tag;
+            if let Some(val) = val_opt {
+                match key.as_str() {
+                    "msg-id" => result.kind = Some(val),
+                    "login" => result.login = Some(val),
+                    "display-name" => result.display_name = Some(unescape_value(&val)),
+                    "user-id" => result.user_id = parse_number(&key, &val).ok(),
+                    "system-msg" => result.system_message = Some(unescape_value(&val)),
+                    "msg-param-cumulative-months" => result.cumulative_months = parse_number(&key, &val).ok(),
+                    "msg-param-sub-plan" => result.sub_plan = Some(val),
+                    "msg-param-recipient-user-name" => result.recipient_login = Some(val),
+                    "msg-param-recipient-display-name" => result.recipient_display_name = Some(unescape_value(&val)),
+                    "msg-param-mass-gift-count" => result.gift_count = parse_number(&key, &val).ok(),
+                    "msg-param-viewerCount" => result.viewer_count = parse_number(&key, &val).ok(),
+                    "msg-param-ritual-name" => result.ritual_name = Some(val),
+                    "tmi-sent-ts" => result.sent_at = parse_timestamp(&val).ok(),
+                    // The other tags are the same as in chat messages, or only useful to display the notice
+                    &_ => {},
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A broken message ID is not a reason to ignore the message, it only cannot be deleted
+fn parse_message_id(value: &str) -> Option<MessageId> {
+    match MessageId::from_str(value) {
+        Ok(id) => Some(id),
+        Err(msg) => {
+            warn!("{}", msg);
+            None
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    T::from_str(value).map_err(|_| format!("Could not parse the {} '{}'", key, value))
+}
+
+/// Reads a timestamp in milliseconds since the epoch
+pub fn parse_timestamp(value: &str) -> Result<Tm, String> {
+    let millis: i64 = try!(parse_number("timestamp", value));
+    Ok(at_utc(Timespec::new(millis / 1000, ((millis % 1000) * 1_000_000) as i32)))
+}
+
+/// Tag values escape the characters that have a meaning in IRC (IRCv3 message tags)
+pub fn unescape_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => result.push(';'),
+                Some('s') => result.push(' '),
+                Some('r') => result.push('\r'),
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                // A trailing backslash is dropped
+                None => {},
+            }
+        }
+        else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use irc::client::data::Message;
+
+    /// Gets the tags of a raw line, as received from Twitch
+    fn raw_tags(line: &str) -> Vec<Tag> {
+        let message: Message = format!("{}\r\n", line).parse().unwrap();
+        message.tags.unwrap()
+    }
+
+    /// Parses the tags of a raw chat message
+    fn tags_of(line: &str) -> MessageTagData {
+        MessageTagData::from_tags(raw_tags(line)).unwrap()
+    }
+
+    #[test]
+    fn privmsg_tags() {
+        let tags = tags_of("@badge-info=;badges=turbo/1;color=#0D4200;display-name=ronni;emotes=25:0-4,12-16/1902:6-10;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=1337;subscriber=0;tmi-sent-ts=1507246572675;turbo=1;user-id=1337;user-type=global_mod :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :Kappa Keepo Kappa");
+        assert_eq!(Some("ronni".to_owned()), tags.display_name);
+        assert_eq!(Some("#0D4200".to_owned()), tags.color);
+        assert_eq!("b34ccfc7-4977-403a-8a94-33c6bac34fb8", tags.id.unwrap().to_string());
+        assert_eq!(Some(1337), tags.user_id);
+        assert_eq!(Some(vec![TwitchBadge::Turbo]), tags.badges);
+        assert_eq!(3, tags.emotes.unwrap().len());
+        assert_eq!(1507246572, tags.sent_at.unwrap().to_timespec().sec);
+        assert!(tags.reply_parent.is_none());
+    }
+
+    #[test]
+    fn bits_tags() {
+        let tags = tags_of("@badge-info=subscriber/14;badges=staff/1,bits/1000;bits=100;color=;display-name=ronni;emotes=;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=12345678;subscriber=0;tmi-sent-ts=1507246572675;turbo=1;user-id=12345678;user-type=staff :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :cheer100");
+        assert_eq!(Some(100), tags.bits);
+        assert_eq!(Some(vec![TwitchBadge::Subscriber(14)]), tags.badge_info);
+        assert_eq!(Some(vec![TwitchBadge::Staff, TwitchBadge::Bits(1000)]), tags.badges);
+    }
+
+    #[test]
+    fn reply_tags() {
+        let tags = tags_of("@badge-info=;badges=;client-nonce=cd56193132f934ac71b4d5ac488d4bd6;color=;display-name=LeftSwing;emotes=;first-msg=0;flags=;id=5b4f63a9-776f-4fce-bf3c-d9707f52e32d;mod=0;reply-parent-display-name=Retrogaming;reply-parent-msg-body=hello\\sthere;reply-parent-msg-id=6b13e51b-7ecb-43b5-ba5b-2bb5288df696;reply-parent-user-id=123456;reply-parent-user-login=retrogaming;returning-chatter=0;room-id=123456;subscriber=0;tmi-sent-ts=1642872963567;turbo=0;user-id=654321;user-type= :leftswing!leftswing@leftswing.tmi.twitch.tv PRIVMSG #retrogaming :@Retrogaming has it");
+        let parent = tags.reply_parent.unwrap();
+        assert_eq!(Some("hello there".to_owned()), parent.body);
+        assert_eq!(Some("retrogaming".to_owned()), parent.login);
+        assert_eq!(Some(123456), parent.user_id);
+        assert_eq!("6b13e51b-7ecb-43b5-ba5b-2bb5288df696", parent.id.unwrap().to_string());
+        assert_eq!(Some(false), tags.first_message);
+        assert_eq!(Some(false), tags.returning_chatter);
+        assert_eq!(Some("cd56193132f934ac71b4d5ac488d4bd6".to_owned()), tags.client_nonce);
+    }
+
+    #[test]
+    fn roomstate_updates() {
+        let parse = |line: &str| RoomStateTags::from_tags(raw_tags(line));
+
+        let mut state = parse("@emote-only=0;followers-only=-1;r9k=0;room-id=12345678;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #bar");
+        assert!(!state.is_on(RoomMode::FollowersOnly));
+        assert!(!state.is_on(RoomMode::Slow));
+        state.merge(parse("@room-id=12345678;slow=10 :tmi.twitch.tv ROOMSTATE #bar"));
+        state.merge(parse("@followers-only=0;room-id=12345678 :tmi.twitch.tv ROOMSTATE #bar"));
+        assert!(state.is_on(RoomMode::Slow));
+        assert!(state.is_on(RoomMode::FollowersOnly));
+        assert!(!state.is_on(RoomMode::EmoteOnly));
+        assert_eq!(Some(12345678), state.room_id);
+        assert_eq!("emote-only off, followers-only 0 minutes, slow 10s, subs-only off, r9k off", state.to_string());
+        assert_eq!("emote-only unknown, followers-only unknown, slow unknown, subs-only unknown, r9k unknown", RoomStateTags::default().to_string());
+    }
+
+    #[test]
+    fn userstate_tags() {
+        let parse = |line: &str| UserStateTags::from_tags(raw_tags(line));
+
+        let moderator = parse("@badge-info=;badges=moderator/1;color=;display-name=Hammer_Bot;emote-sets=0,300374282;mod=1;subscriber=0;user-type=mod :tmi.twitch.tv USERSTATE #dallas");
+        assert!(moderator.can_moderate());
+        assert_eq!(Some("Hammer_Bot".to_owned()), moderator.display_name);
+
+        let streamer = parse("@badge-info=;badges=broadcaster/1;color=;display-name=dallas;emote-sets=0;mod=0;subscriber=0;user-type= :tmi.twitch.tv USERSTATE #dallas");
+        assert!(streamer.can_moderate());
+
+        // The irc crate needs a space after a command without parameters
+        let global = parse("@badge-info=;badges=;color=#0D4200;display-name=Hammer_Bot;emote-sets=0,33,50;user-id=141981764;user-type= :tmi.twitch.tv GLOBALUSERSTATE ");
+        assert!(!global.can_moderate());
+        assert_eq!(Some(141981764), global.user_id);
+    }
+
+    #[test]
+    fn usernotice_tags() {
+        let parse = |line: &str| UserNoticeTags::from_tags(raw_tags(line));
+
+        let resub = parse("@badge-info=;badges=staff/1,broadcaster/1,turbo/1;color=#008000;display-name=ronni;emotes=;id=db25007f-7a18-43eb-9379-80131e44d633;login=ronni;mod=0;msg-id=resub;msg-param-cumulative-months=6;msg-param-streak-months=2;msg-param-should-share-streak=1;msg-param-sub-plan=Prime;msg-param-sub-plan-name=Prime;room-id=12345678;subscriber=1;system-msg=ronni\\shas\\ssubscribed\\sfor\\s6\\smonths!;tmi-sent-ts=1507246572675;turbo=1;user-id=87654321;user-type=staff :tmi.twitch.tv USERNOTICE #dallas :Great stream -- keep it up!");
+        assert_eq!(Some("resub".to_owned()), resub.kind);
+        assert_eq!(Some(6), resub.cumulative_months);
+        assert_eq!(Some("ronni has subscribed for 6 months!".to_owned()), resub.system_message);
+
+        let gift = parse("@badge-info=;badges=staff/1,premium/1;color=#0000FF;display-name=TWW2;emotes=;id=e9176cd8-5e22-4684-ad40-ce53c2561c5e;login=tww2;mod=0;msg-id=subgift;msg-param-months=1;msg-param-recipient-display-name=Mr_Woodchuck;msg-param-recipient-id=55554444;msg-param-recipient-user-name=mr_woodchuck;msg-param-sub-plan-name=House\\sof\\sNyoro~n;msg-param-sub-plan=1000;room-id=19571752;subscriber=0;system-msg=TWW2\\sgifted\\sa\\sTier\\s1\\ssub\\sto\\sMr_Woodchuck!;tmi-sent-ts=1521159445153;turbo=0;user-id=87654321;user-type=staff :tmi.twitch.tv USERNOTICE #forstycup");
+        assert_eq!(Some("tww2".to_owned()), gift.login);
+        assert_eq!(Some("mr_woodchuck".to_owned()), gift.recipient_login);
+
+        let raid = parse("@badge-info=;badges=turbo/1;color=#9ACD32;display-name=TestChannel;emotes=;id=3d830f12-795c-447d-af3c-ea05e40fbddb;login=testchannel;mod=0;msg-id=raid;msg-param-displayName=TestChannel;msg-param-login=testchannel;msg-param-viewerCount=15;room-id=33332222;subscriber=0;system-msg=15\\sraiders\\sfrom\\sTestChannel\\shave\\sjoined\\n!;tmi-sent-ts=1507246572675;turbo=1;user-id=123456;user-type= :tmi.twitch.tv USERNOTICE #othertestchannel");
+        assert_eq!(Some(15), raid.viewer_count);
+        assert_eq!(1507246572, raid.sent_at.unwrap().to_timespec().sec);
+        assert_eq!(Some(123456), raid.user_id);
+
+        let ritual = parse("@badge-info=;badges=;color=;display-name=SevenTest1;emotes=30259:0-6;id=37feed0f-b9c7-4c3a-b475-21c6c6d21c3d;login=seventest1;mod=0;msg-id=ritual;msg-param-ritual-name=new_chatter;room-id=87654321;subscriber=0;system-msg=Seventoes\\sis\\snew\\shere!;tmi-sent-ts=1508363903826;turbo=0;user-id=77776666;user-type= :tmi.twitch.tv USERNOTICE #seventoes :HeyGuys");
+        assert_eq!(Some("new_chatter".to_owned()), ritual.ritual_name);
+    }
+
+    #[test]
+    fn large_ids() {
+        let tags = tags_of("@id=5b4f63a9-776f-4fce-bf3c-d9707f52e32d;room-id=9876543210;user-id=8765432109 :a!a@a.tmi.twitch.tv PRIVMSG #a :hi");
+        assert_eq!(Some(9876543210), tags.room_id);
+        assert_eq!(Some(8765432109), tags.user_id);
+        assert!(MessageId::from_str("not-a-uuid").is_err());
+        assert!(MessageId::from_str("5b4f63a9-776f-4fce-bf3c-d9707f52e32g").is_err());
+    }
+}
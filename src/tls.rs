@@ -0,0 +1,119 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::yield_now;
+use std::time::{Duration, Instant};
+
+use irc::client::conn::Connection;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+/// How long a read may hold the stream before letting a pending write through
+const READ_SLICE_MS: u64 = 100;
+/// How long a read waits for a line before giving up with an empty one
+const IDLE_TICK_MS: u64 = 1000;
+
+struct TlsStream {
+    reader: BufReader<SslStream<TcpStream>>,
+    /// Bytes of a line that has not been completely received yet
+    partial_line: Vec<u8>,
+}
+
+/// IRC connection over TLS, checking the server certificate.
+/// An OpenSSL stream cannot be split into a reader and a writer, so reads are done in short
+/// slices: this lets the sending thread use the stream while we wait for the next line.
+/// Twitch only talks UTF-8, so the encoding requested by the IRC library is ignored.
+/// When no line arrives for a second, an empty line is returned instead: the IRC library cannot parse it,
+/// and the chat takes it as a tick to run its timers (see `Chat::is_idle_tick`).
+pub struct TlsConnection {
+    host: String,
+    port: u16,
+    ca_file: Option<PathBuf>,
+    stream: Mutex<TlsStream>,
+}
+
+impl TlsConnection {
+    /// Connects to the server. If a CA file is given, it is trusted in addition to the system
+    /// certificates, which allows testing against a server with a self-signed certificate.
+    pub fn connect(host: &str, port: u16, ca_file: Option<PathBuf>) -> Result<TlsConnection> {
+        let stream = try!(TlsConnection::open_stream(host, port, ca_file.as_ref()));
+        Ok(TlsConnection {
+            host: host.to_owned(),
+            port: port,
+            ca_file: ca_file,
+            stream: Mutex::new(stream),
+        })
+    }
+
+    fn open_stream(host: &str, port: u16, ca_file: Option<&PathBuf>) -> Result<TlsStream> {
+        let mut builder = try!(SslConnector::builder(SslMethod::tls()).map_err(to_io_error));
+        if let Some(path) = ca_file {
+            try!(builder.set_ca_file(path).map_err(to_io_error));
+        }
+        let connector = builder.build();
+
+        let socket = try!(TcpStream::connect((host, port)));
+        try!(socket.set_read_timeout(Some(Duration::from_millis(READ_SLICE_MS))));
+        let stream = try!(connector.connect(host, socket).map_err(|err| Error::new(ErrorKind::Other, format!("TLS handshake failed: {}", err))));
+        Ok(TlsStream {
+            reader: BufReader::new(stream),
+            partial_line: Vec::new(),
+        })
+    }
+}
+
+fn to_io_error<E: ::std::error::Error + Send + Sync + 'static>(err: E) -> Error {
+    Error::new(ErrorKind::Other, err)
+}
+
+impl Connection for TlsConnection {
+    fn send(&self, msg: &str, _: &str) -> Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let socket = stream.reader.get_mut();
+        try!(socket.write_all(msg.as_bytes()));
+        socket.flush()
+    }
+
+    fn recv(&self, _: &str) -> Result<String> {
+        let start = Instant::now();
+        loop {
+            {
+                let mut guard = self.stream.lock().unwrap();
+                let stream = &mut *guard;
+                match stream.reader.read_until(b'\n', &mut stream.partial_line) {
+                    Ok(0) => {
+                        // The server closed the connection in the middle of a line, which cannot be parsed anyway
+                        if !stream.partial_line.is_empty() {
+                            warn!("Connection closed before the end of a line; dropped '{}'", String::from_utf8_lossy(&stream.partial_line));
+                            stream.partial_line.clear();
+                        }
+                        // The IRC library looks for this exact message
+                        return Err(Error::new(ErrorKind::Other, "EOF"));
+                    },
+                    Ok(_) => {
+                        let line = String::from_utf8_lossy(&stream.partial_line).into_owned();
+                        stream.partial_line.clear();
+                        return Ok(line);
+                    },
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {},
+                    Err(err) => return Err(err),
+                }
+            }
+            if start.elapsed() >= Duration::from_millis(IDLE_TICK_MS) {
+                return Ok(String::new());
+            }
+            // Nothing yet; give the writers a chance
+            yield_now();
+        }
+    }
+
+    fn written(&self, _: &str) -> Option<String> {
+        None
+    }
+
+    fn reconnect(&self) -> Result<()> {
+        let stream = try!(TlsConnection::open_stream(&self.host, self.port, self.ca_file.as_ref()));
+        *self.stream.lock().unwrap() = stream;
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashSet, VecDeque};
+
+use time::{Duration, Tm};
+use yaml_rust::yaml::Yaml;
+
+use checker::Action;
+use normalize::Normalization;
+use tags::MessageId;
+
+/// Rule ID reported for the users caught in a wave
+pub const WAVE_RULE_ID : &'static str = "wave-detector";
+
+/// How two messages are compared by the wave detector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Similarity {
+    /// The normalized texts must be equal
+    Exact,
+    /// Share of common character trigrams (Jaccard index), good against random suffixes
+    Trigrams,
+    /// Edit distance relative to the length of the longest text, good against swapped characters
+    Levenshtein,
+}
+
+impl Similarity {
+    fn from_name(name: &str) -> Option<Similarity> {
+        match name {
+            "exact" => Some(Similarity::Exact),
+            "trigrams" => Some(Similarity::Trigrams),
+            "levenshtein" => Some(Similarity::Levenshtein),
+            _ => None,
+        }
+    }
+
+    /// Returns a score between 0 (nothing in common) and 1 (same text)
+    pub fn score(&self, a: &str, b: &str) -> f64 {
+        if a == b {
+            return 1.0;
+        }
+
+        match self {
+            &Similarity::Exact => 0.0,
+            &Similarity::Trigrams => {
+                let a_trigrams = trigrams(a);
+                let b_trigrams = trigrams(b);
+                let union = a_trigrams.union(&b_trigrams).count();
+                if union == 0 {
+                    0.0
+                }
+                else {
+                    a_trigrams.intersection(&b_trigrams).count() as f64 / union as f64
+                }
+            },
+            &Similarity::Levenshtein => {
+                let a_chars: Vec<char> = a.chars().collect();
+                let b_chars: Vec<char> = b.chars().collect();
+                let longest = if a_chars.len() > b_chars.len() { a_chars.len() } else { b_chars.len() };
+                1.0 - levenshtein(&a_chars, &b_chars) as f64 / longest as f64
+            },
+        }
+    }
+}
+
+fn trigrams(text: &str) -> HashSet<(char, char, char)> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(3).map(|w| (w[0], w[1], w[2])).collect()
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 0..a.len() {
+        current[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = previous[j] + if a[i] == b[j] { 0 } else { 1 };
+            let insertion = current[j] + 1;
+            let deletion = previous[j + 1] + 1;
+            current[j + 1] = *[substitution, insertion, deletion].iter().min().unwrap();
+        }
+        ::std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[derive(Debug, Clone)]
+pub struct WaveConfig {
+    /// Number of distinct users posting similar messages that makes a wave
+    pub users: usize,
+    /// Size of the sliding window, in seconds
+    pub window: i64,
+    pub similarity: Similarity,
+    /// Minimum score for two messages to be considered similar
+    pub threshold: f64,
+    /// Messages shorter than this (once normalized) are ignored, so that everyone typing "F" is not a wave
+    pub min_length: usize,
+    /// Turns the hammer mode on when a wave is detected
+    pub auto_hammer: bool,
+    /// What to do with the wave members when the hammer mode is on
+    pub action: Action,
+}
+
+impl WaveConfig {
+    pub fn from_yaml(token: &Yaml) -> Result<WaveConfig, String> {
+        let users = match token["users"] {
+            Yaml::Integer(value) if value >= 2 => value as usize,
+            _ => return Err(format!("'users' should be a number greater than 1")),
+        };
+
+        let window = match token["window"] {
+            Yaml::Integer(value) if value > 0 => value,
+            _ => return Err(format!("'window' should be a positive number of seconds")),
+        };
+
+        let similarity = match token["similarity"] {
+            Yaml::String(ref name) => try!(Similarity::from_name(name).ok_or(format!(
+                "unknown similarity '{}' (expected exact, trigrams or levenshtein)", name))),
+            Yaml::BadValue => Similarity::Trigrams,
+            _ => return Err(format!("'similarity' should be a string")),
+        };
+
+        let threshold = match token["threshold"] {
+            Yaml::Real(_) => match token["threshold"].as_f64() {
+                Some(value) if value > 0.0 && value <= 1.0 => value,
+                _ => return Err(format!("'threshold' should be between 0 and 1")),
+            },
+            Yaml::Integer(1) => 1.0,
+            Yaml::BadValue => 0.8,
+            _ => return Err(format!("'threshold' should be between 0 and 1")),
+        };
+
+        let min_length = match token["min_length"] {
+            Yaml::Integer(value) if value >= 0 => value as usize,
+            Yaml::BadValue => 10,
+            _ => return Err(format!("'min_length' should be a positive number")),
+        };
+
+        let auto_hammer = match token["auto_hammer"] {
+            Yaml::Boolean(value) => value,
+            Yaml::BadValue => false,
+            _ => return Err(format!("'auto_hammer' should be true or false")),
+        };
+
+        let action = match token["action"] {
+            Yaml::String(ref value) => try!(Action::parse(value)),
+            Yaml::BadValue => Action::Ban,
+            _ => return Err(format!("'action' should be a string")),
+        };
+
+        Ok(WaveConfig {
+            users: users,
+            window: window,
+            similarity: similarity,
+            threshold: threshold,
+            min_length: min_length,
+            auto_hammer: auto_hammer,
+            action: action,
+        })
+    }
+}
+
+struct RecentMessage {
+    date: Tm,
+    nickname: String,
+    message_id: Option<MessageId>,
+    text: String,
+    /// The author was already reported for being part of a wave
+    flagged: bool,
+}
+
+/// A user caught in a wave, with the IDs of their similar messages (for the delete action)
+#[derive(Debug, PartialEq)]
+pub struct WaveMember {
+    pub nickname: String,
+    pub message_ids: Vec<MessageId>,
+}
+
+/// Detects many users posting the same (or nearly the same) message in a short time
+pub struct WaveDetector {
+    config: WaveConfig,
+    normalization: Normalization,
+    recent: VecDeque<RecentMessage>,
+}
+
+impl WaveDetector {
+    pub fn new(config: WaveConfig) -> WaveDetector {
+        WaveDetector {
+            config: config,
+            normalization: Normalization::all(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &WaveConfig {
+        &self.config
+    }
+
+    /// Adds a message to the window. If it completes a wave, returns the members of the wave
+    /// that were not reported yet (see `mark_reported`), once each.
+    pub fn push(&mut self, date: Tm, nickname: &str, message_id: Option<MessageId>, text: &str) -> Vec<WaveMember> {
+        let window_start = date - Duration::seconds(self.config.window);
+        while self.recent.front().map_or(false, |m| m.date < window_start) {
+            self.recent.pop_front();
+        }
+
+        let text = self.normalization.apply(text).to_lowercase();
+        if text.chars().count() < self.config.min_length {
+            return Vec::new();
+        }
+
+        self.recent.push_back(RecentMessage {
+            date: date,
+            nickname: nickname.to_owned(),
+            message_id: message_id,
+            text: text,
+            flagged: false,
+        });
+
+        let (similar, users) = {
+            let newest = self.recent.back().unwrap();
+            let similar: Vec<usize> = self.recent.iter()
+                .enumerate()
+                .filter(|&(_, m)| self.config.similarity.score(&m.text, &newest.text) >= self.config.threshold)
+                .map(|(pos, _)| pos)
+                .collect();
+            let users: HashSet<&str> = similar.iter().map(|&pos| self.recent[pos].nickname.as_str()).collect();
+            (similar, users.len())
+        };
+
+        let mut result: Vec<WaveMember> = Vec::new();
+        if users >= self.config.users {
+            for pos in similar {
+                let message = &self.recent[pos];
+                if message.flagged {
+                    continue;
+                }
+                if !result.iter().any(|member| member.nickname == message.nickname) {
+                    result.push(WaveMember {
+                        nickname: message.nickname.clone(),
+                        message_ids: Vec::new(),
+                    });
+                }
+                if let Some(id) = message.message_id {
+                    let member = result.iter_mut().find(|member| member.nickname == message.nickname).unwrap();
+                    member.message_ids.push(id);
+                }
+            }
+        }
+        result
+    }
+
+    /// Stops reporting the messages of a user, once the wave they are part of was reported
+    pub fn mark_reported(&mut self, nickname: &str) {
+        for message in self.recent.iter_mut().filter(|m| m.nickname == nickname) {
+            message.flagged = true;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::now_utc;
+
+    fn test_config() -> WaveConfig {
+        WaveConfig {
+            users: 3,
+            window: 10,
+            similarity: Similarity::Trigrams,
+            threshold: 0.6,
+            min_length: 5,
+            auto_hammer: true,
+            action: Action::Ban,
+        }
+    }
+
+    #[test]
+    fn wave_detected() {
+        let mut detector = WaveDetector::new(test_config());
+        let start = now_utc();
+        assert!(detector.push(start, "bot1", None, "get free followers at example.com 1234").is_empty());
+        assert!(detector.push(start, "human", None, "what a play").is_empty());
+        let first_id = "885196de-cb67-427a-baa8-82f9b0fcd001".parse().ok();
+        let second_id = "885196de-cb67-427a-baa8-82f9b0fcd002".parse().ok();
+        assert!(detector.push(start, "bot2", first_id, "get free followers at example.com 9876").is_empty());
+        // Same user twice does not count
+        assert!(detector.push(start, "bot2", second_id, "get free followers at example.com 5555").is_empty());
+        let members = detector.push(start, "bot3", None, "Get free followers at example.com!");
+        let nicknames: Vec<&str> = members.iter().map(|member| member.nickname.as_str()).collect();
+        assert_eq!(vec!["bot1", "bot2", "bot3"], nicknames);
+        assert_eq!(vec![first_id.unwrap(), second_id.unwrap()], members[1].message_ids);
+        // Nobody was reported yet, so everyone is reported again
+        assert_eq!(3, detector.push(start, "bot3", None, "get free followers at example.com 4321").len());
+        for member in members {
+            detector.mark_reported(&member.nickname);
+        }
+        // Latecomers are reported alone
+        assert_eq!(1, detector.push(start + Duration::seconds(5), "bot4", None, "get free followers at example.com").len());
+        // Out of the window
+        assert!(detector.push(start + Duration::seconds(30), "bot5", None, "get free followers at example.com").is_empty());
+    }
+
+    #[test]
+    fn similarity_scores() {
+        assert_eq!(1.0, Similarity::Exact.score("abc", "abc"));
+        assert_eq!(0.0, Similarity::Exact.score("abc", "abd"));
+        assert_eq!(0.75, Similarity::Levenshtein.score("abcd", "abed"));
+    }
+}